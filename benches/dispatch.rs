@@ -0,0 +1,113 @@
+//! Delivery to many subscriptions on one connection while one of them lags
+//! far behind, comparing `Overflow::Block` with the default.
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::stream::StreamExt;
+use tokio::io::duplex;
+use tokio::runtime::{Builder, Runtime};
+use tokio::time::delay_for;
+
+use stomping::*;
+
+const SUBSCRIPTIONS: usize = 100;
+const MESSAGES: usize = 10;
+const TOPIC: &str = "/topic/bench";
+// How long the stalled consumer takes over each message.
+const STALL: Duration = Duration::from_millis(1);
+
+struct Fixture {
+    runtime: Runtime,
+    client: Client,
+    received: UnboundedReceiver<()>,
+}
+
+impl Fixture {
+    fn new(overflow: Overflow) -> Self {
+        let mut runtime = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .expect("runtime");
+        let (client, received) = runtime.block_on(async {
+            let broker = Broker::new();
+            let (conn, client) = Client::builder()
+                .transport(move || {
+                    let (client_side, server_side) = duplex(64 * 1024);
+                    let broker = broker.clone();
+                    tokio::spawn(async move { broker.serve(server_side).await });
+                    async { Ok(client_side) }
+                })
+                .connect()
+                .await
+                .expect("connect");
+            tokio::spawn(conn);
+
+            let options = SubscribeOptions::default().buffer(16).overflow(overflow);
+            let mut stalled = client
+                .subscribe_with(TOPIC, "stalled", options)
+                .await
+                .expect("subscribe");
+            tokio::spawn(async move {
+                while stalled.next().await.is_some() {
+                    delay_for(STALL).await;
+                }
+            });
+
+            let (tx, received) = unbounded();
+            for n in 1..SUBSCRIPTIONS {
+                let mut sub = client
+                    .subscribe_with_receipt(
+                        TOPIC,
+                        &format!("sub-{}", n),
+                        AckMode::Auto,
+                        Default::default(),
+                    )
+                    .await
+                    .expect("subscribe");
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    while sub.next().await.is_some() {
+                        if tx.unbounded_send(()).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            (client, received)
+        });
+
+        Fixture {
+            runtime,
+            client,
+            received,
+        }
+    }
+
+    fn publish_and_receive(&mut self) {
+        let client = &self.client;
+        let received = &mut self.received;
+        self.runtime.block_on(async {
+            for _ in 0..MESSAGES {
+                client.publish(TOPIC, b"hello").await.expect("publish");
+            }
+            for _ in 0..MESSAGES * (SUBSCRIPTIONS - 1) {
+                received.next().await.expect("message");
+            }
+        });
+    }
+}
+
+fn stalled_consumer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stalled_consumer");
+    for &(name, overflow) in &[("block", Overflow::Block), ("spill", Overflow::Spill)] {
+        let mut fixture = Fixture::new(overflow);
+        group.bench_function(name, |b| b.iter(|| fixture.publish_and_receive()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, stalled_consumer);
+criterion_main!(benches);
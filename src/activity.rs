@@ -0,0 +1,107 @@
+use std::{
+    io,
+    mem::MaybeUninit,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+
+/// When bytes were last read from and written to a transport, so that
+/// heartbeats follow the wire rather than whole frames.
+#[derive(Clone, Debug)]
+pub(crate) struct Activity {
+    times: Arc<Mutex<Times>>,
+}
+
+#[derive(Debug)]
+struct Times {
+    read: Instant,
+    written: Instant,
+}
+
+// Records activity on the wrapped transport as bytes pass through it.
+pub(crate) struct Tracked<T> {
+    inner: T,
+    activity: Activity,
+}
+
+impl Activity {
+    pub(crate) fn new() -> Self {
+        let now = Instant::now();
+        Activity {
+            times: Arc::new(Mutex::new(Times {
+                read: now,
+                written: now,
+            })),
+        }
+    }
+
+    pub(crate) fn last_read(&self) -> Instant {
+        self.times.lock().expect("activity lock").read
+    }
+
+    pub(crate) fn last_written(&self) -> Instant {
+        self.times.lock().expect("activity lock").written
+    }
+
+    pub(crate) fn read(&self) {
+        self.times.lock().expect("activity lock").read = Instant::now();
+    }
+
+    pub(crate) fn written(&self) {
+        self.times.lock().expect("activity lock").written = Instant::now();
+    }
+}
+
+impl<T> Tracked<T> {
+    pub(crate) fn new(inner: T, activity: Activity) -> Self {
+        Tracked { inner, activity }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Tracked<T> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [MaybeUninit<u8>]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                self.activity.read();
+            }
+        }
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Tracked<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                self.activity.written();
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
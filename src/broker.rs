@@ -0,0 +1,704 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use futures::{
+    future::{self, FutureExt},
+    select,
+    stream::{FuturesUnordered, StreamExt},
+};
+use log::*;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+use crate::connection::DEFAULT_HEARTBEAT_GRACE;
+use crate::errors::*;
+use crate::message::Message;
+use crate::protocol::{AckMode, Version};
+use crate::server::{accept_with_transport, Request, RequestKind, Responder};
+
+const TOPIC_PREFIX: &str = "/topic/";
+
+/// A small in-memory STOMP broker, for tests.
+///
+/// Destinations under `/topic/` are delivered to every current subscriber.
+/// Anything else is a queue, which holds messages until there is a
+/// subscriber, and hands them out to its subscribers in turn. Messages that
+/// are nacked, or left unacknowledged when their subscriber goes away, are
+/// put back on their queue. Clones of a `Broker` share their destinations.
+#[derive(Clone, Debug, Default)]
+pub struct Broker {
+    heartbeat: Option<Duration>,
+    heartbeat_grace: Option<u32>,
+    state: Arc<Mutex<BrokerState>>,
+}
+
+#[derive(Debug, Default)]
+struct BrokerState {
+    next_id: u64,
+    round_robin: usize,
+    sessions: BTreeMap<u64, Session>,
+    queues: BTreeMap<String, VecDeque<Stored>>,
+}
+
+#[derive(Debug)]
+struct Session {
+    responder: Responder,
+    subscriptions: BTreeMap<String, BrokerSubscription>,
+    // Deliveries awaiting an ACK or NACK, oldest first.
+    unacked: Vec<Delivery>,
+    transactions: BTreeMap<String, Vec<Request>>,
+}
+
+impl Session {
+    // Marks which of `unacked` an ACK or NACK of `ack_id` settles; in
+    // `client` mode, that includes earlier deliveries to the same
+    // subscription.
+    fn settled_by(
+        &self,
+        unacked: &[&Delivery],
+        ack_id: &str,
+    ) -> std::result::Result<Vec<bool>, String> {
+        let pos = unacked
+            .iter()
+            .position(|d| d.ack_id == ack_id)
+            .ok_or_else(|| "Unknown ack id".to_string())?;
+        let subscription = &unacked[pos].subscription;
+        let cumulative = self
+            .subscriptions
+            .get(subscription)
+            .is_some_and(|sub| sub.ack == AckMode::Client);
+        Ok(unacked
+            .iter()
+            .enumerate()
+            .map(|(i, d)| i == pos || (cumulative && i < pos && &d.subscription == subscription))
+            .collect())
+    }
+}
+
+#[derive(Debug)]
+struct BrokerSubscription {
+    destination: String,
+    ack: AckMode,
+}
+
+#[derive(Debug)]
+struct Delivery {
+    ack_id: String,
+    subscription: String,
+    message: Stored,
+}
+
+#[derive(Clone, Debug)]
+struct Stored {
+    id: String,
+    message: Message,
+}
+
+// A refusal, reported to the client in an ERROR frame.
+type Handled = std::result::Result<(), String>;
+
+impl Broker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Offer heartbeats at the given interval in both directions.
+    pub fn with_heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    /// How many of its heartbeat intervals a client may stay silent before
+    /// its session is closed. Defaults to 2.
+    pub fn with_heartbeat_grace(mut self, intervals: u32) -> Self {
+        self.heartbeat_grace = Some(intervals);
+        self
+    }
+
+    /// Serves each connection accepted from `listener`, until accepting fails.
+    pub async fn listen(self, mut listener: TcpListener) -> Result<()> {
+        let mut sessions = FuturesUnordered::new();
+        loop {
+            select! {
+                res = listener.accept().fuse() => {
+                    let (sock, peer) = res?;
+                    debug!("Accepted connection from {:?}", peer);
+                    sessions.push(self.serve(sock));
+                }
+                res = sessions.select_next_some() => {
+                    if let Err(e) = res {
+                        warn!("Session failed: {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serves a single client connection until it disconnects.
+    pub async fn serve<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        &self,
+        transport: T,
+    ) -> Result<()> {
+        let grace = self.heartbeat_grace.unwrap_or(DEFAULT_HEARTBEAT_GRACE);
+        let (conn, mut session) = accept_with_transport(transport, self.heartbeat, grace).await?;
+        let session_id = self.lock().open_session(session.responder());
+        debug!("Session {} connected", session_id);
+
+        let process = async move {
+            while let Some(request) = session.next().await {
+                if !self.lock().received(session_id, request) {
+                    break;
+                }
+            }
+            // Anything left unacknowledged goes back on its queue.
+            self.lock().close_session(session_id);
+        };
+        let (res, ()) = future::join(conn, process).await;
+        debug!("Session {} closed: {:?}", session_id, res);
+        res
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BrokerState> {
+        self.state.lock().expect("broker state poisoned")
+    }
+}
+
+impl BrokerState {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn open_session(&mut self, responder: Responder) -> u64 {
+        let id = self.next_id();
+        let session = Session {
+            responder,
+            subscriptions: BTreeMap::new(),
+            unacked: Vec::new(),
+            transactions: BTreeMap::new(),
+        };
+        self.sessions.insert(id, session);
+        id
+    }
+
+    fn session(&mut self, session_id: u64) -> &mut Session {
+        self.sessions
+            .get_mut(&session_id)
+            .expect("request from a closed session")
+    }
+
+    // Returns false once the session should be closed.
+    fn received(&mut self, session_id: u64, request: Request) -> bool {
+        let disconnect = request.kind == RequestKind::Disconnect;
+        let transaction = match &request.kind {
+            RequestKind::Send { transaction, .. }
+            | RequestKind::Ack { transaction, .. }
+            | RequestKind::Nack { transaction, .. } => transaction.clone(),
+            _ => None,
+        };
+        let res = match transaction {
+            Some(transaction) => {
+                match self.session(session_id).transactions.get_mut(&transaction) {
+                    Some(pending) => {
+                        pending.push(request.clone());
+                        Ok(())
+                    }
+                    None => Err("Unknown transaction".to_string()),
+                }
+            }
+            None => self.apply(session_id, request.clone()),
+        };
+
+        let responder = self.session(session_id).responder.clone();
+        let res = match res {
+            Ok(()) => request
+                .receipt
+                .map_or(Ok(()), |receipt_id| responder.receipt(&receipt_id)),
+            Err(message) => {
+                debug!("Session {}: refusing request: {}", session_id, message);
+                let _ = responder.error(&message, request.receipt.as_deref());
+                return false;
+            }
+        };
+        res.is_ok() && !disconnect
+    }
+
+    fn apply(&mut self, session_id: u64, request: Request) -> Handled {
+        match request.kind {
+            RequestKind::Send {
+                destination, body, ..
+            } => {
+                let mut headers = request.headers;
+                for name in &["destination", "receipt", "transaction", "content-length"] {
+                    headers.remove(name.as_bytes());
+                }
+                let id = format!("message-{}", self.next_id());
+                let message = Message {
+                    destination,
+                    headers,
+                    body,
+                };
+                self.publish(Stored { id, message });
+            }
+            RequestKind::Subscribe {
+                destination,
+                id,
+                ack,
+            } => {
+                let session = self.session(session_id);
+                if session.subscriptions.contains_key(&id) {
+                    return Err("Subscription id already in use".to_string());
+                }
+                let subscription = BrokerSubscription {
+                    destination: destination.clone(),
+                    ack,
+                };
+                session.subscriptions.insert(id, subscription);
+                self.drain(&destination);
+            }
+            RequestKind::Unsubscribe { id } => {
+                let session = self.session(session_id);
+                if session.subscriptions.remove(&id).is_none() {
+                    return Err("Unknown subscription".to_string());
+                }
+                let (pending, kept) = std::mem::take(&mut session.unacked)
+                    .into_iter()
+                    .partition(|d| d.subscription == id);
+                session.unacked = kept;
+                self.requeue(pending);
+            }
+            RequestKind::Ack { id, .. } => {
+                self.take_acked(session_id, &id)?;
+            }
+            RequestKind::Nack { id, .. } => {
+                let nacked = self.take_acked(session_id, &id)?;
+                self.requeue(nacked);
+            }
+            RequestKind::Begin { transaction } => {
+                let session = self.session(session_id);
+                if session.transactions.contains_key(&transaction) {
+                    return Err("Transaction already begun".to_string());
+                }
+                session.transactions.insert(transaction, Vec::new());
+            }
+            RequestKind::Commit { transaction } => {
+                let pending = self
+                    .session(session_id)
+                    .transactions
+                    .remove(&transaction)
+                    .ok_or_else(|| "Unknown transaction".to_string())?;
+                self.check_transaction(session_id, &pending)?;
+                for request in pending {
+                    self.apply(session_id, request)?;
+                }
+            }
+            RequestKind::Abort { transaction } => {
+                self.session(session_id)
+                    .transactions
+                    .remove(&transaction)
+                    .ok_or_else(|| "Unknown transaction".to_string())?;
+            }
+            RequestKind::Disconnect => {}
+        }
+        Ok(())
+    }
+
+    fn publish(&mut self, stored: Stored) {
+        let destination = stored.message.destination.clone();
+        if destination.starts_with(TOPIC_PREFIX) {
+            for (session_id, subscription) in self.subscribers(&destination) {
+                self.deliver(session_id, subscription, stored.clone());
+            }
+        } else {
+            self.queues
+                .entry(destination.clone())
+                .or_default()
+                .push_back(stored);
+            self.drain(&destination);
+        }
+    }
+
+    // Hands queued messages out to the queue's subscribers, in turn.
+    fn drain(&mut self, destination: &str) {
+        let subscribers = self.subscribers(destination);
+        if subscribers.is_empty() {
+            return;
+        }
+        while let Some(stored) = self
+            .queues
+            .get_mut(destination)
+            .and_then(VecDeque::pop_front)
+        {
+            self.round_robin = self.round_robin.wrapping_add(1);
+            let (session_id, subscription) =
+                subscribers[self.round_robin % subscribers.len()].clone();
+            self.deliver(session_id, subscription, stored);
+        }
+        self.queues.remove(destination);
+    }
+
+    fn subscribers(&self, destination: &str) -> Vec<(u64, String)> {
+        self.sessions
+            .iter()
+            .flat_map(|(session_id, session)| {
+                session
+                    .subscriptions
+                    .iter()
+                    .filter(|(_, sub)| sub.destination == destination)
+                    .map(move |(id, _)| (*session_id, id.clone()))
+            })
+            .collect()
+    }
+
+    fn deliver(&mut self, session_id: u64, subscription: String, stored: Stored) {
+        let ack_id = format!("ack-{}", self.next_id());
+        let session = self.session(session_id);
+        // Before 1.2, clients acknowledge by `message-id`.
+        let ack_id = if session.responder.version() >= Version::V1_2 {
+            ack_id
+        } else {
+            stored.id.clone()
+        };
+        let ack = match session.subscriptions[&subscription].ack {
+            AckMode::Auto => None,
+            AckMode::Client | AckMode::ClientIndividual => Some(ack_id.clone()),
+        };
+
+        let res = session.responder.message(
+            &subscription,
+            &stored.id,
+            ack.as_deref(),
+            stored.message.clone(),
+        );
+        if res.is_err() {
+            debug!("Session {} gone; dropping delivery", session_id);
+        }
+        if ack.is_some() {
+            session.unacked.push(Delivery {
+                ack_id,
+                subscription,
+                message: stored,
+            });
+        }
+    }
+
+    // Checks that each ACK and NACK in a transaction settles a delivery, so
+    // that committing it applies either all of its requests or none.
+    fn check_transaction(&self, session_id: u64, pending: &[Request]) -> Handled {
+        let session = &self.sessions[&session_id];
+        let mut unacked: Vec<&Delivery> = session.unacked.iter().collect();
+        for request in pending {
+            match &request.kind {
+                RequestKind::Ack { id, .. } | RequestKind::Nack { id, .. } => {
+                    let mut settled = session.settled_by(&unacked, id)?.into_iter();
+                    unacked.retain(|_| !settled.next().unwrap_or_default());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Removes the deliveries settled by an ACK or NACK.
+    fn take_acked(
+        &mut self,
+        session_id: u64,
+        ack_id: &str,
+    ) -> std::result::Result<Vec<Delivery>, String> {
+        let session = self.session(session_id);
+        let settled = session.settled_by(&session.unacked.iter().collect::<Vec<_>>(), ack_id)?;
+
+        let mut taken = Vec::new();
+        for (delivery, settled) in std::mem::take(&mut session.unacked)
+            .into_iter()
+            .zip(settled)
+        {
+            if settled {
+                taken.push(delivery);
+            } else {
+                session.unacked.push(delivery);
+            }
+        }
+        Ok(taken)
+    }
+
+    // Puts deliveries back at the head of their queues, in their original
+    // order. Topic messages are dropped.
+    fn requeue(&mut self, deliveries: Vec<Delivery>) {
+        let mut destinations = Vec::new();
+        for delivery in deliveries.into_iter().rev() {
+            let destination = delivery.message.message.destination.clone();
+            if destination.starts_with(TOPIC_PREFIX) {
+                continue;
+            }
+            if !destinations.contains(&destination) {
+                destinations.push(destination.clone());
+            }
+            self.queues
+                .entry(destination)
+                .or_default()
+                .push_front(delivery.message);
+        }
+        for destination in destinations {
+            self.drain(&destination);
+        }
+    }
+
+    fn close_session(&mut self, session_id: u64) {
+        if let Some(session) = self.sessions.remove(&session_id) {
+            self.requeue(session.unacked);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::sink::SinkExt;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio_util::codec::Framed;
+
+    use crate::connection::{wrap, StompCodec};
+    use crate::protocol::{Command, Frame, FrameOrKeepAlive};
+
+    type Peer = Framed<DuplexStream, StompCodec>;
+
+    fn frame(command: Command, headers: &[(&str, &str)]) -> Frame {
+        Frame {
+            command,
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    async fn send(peer: &mut Peer, frame: Frame) {
+        peer.send(FrameOrKeepAlive::Frame(frame))
+            .await
+            .expect("send");
+    }
+
+    async fn next_frame(peer: &mut Peer) -> Frame {
+        loop {
+            match peer.next().await.expect("frame").expect("decode") {
+                FrameOrKeepAlive::Frame(frame) => return frame,
+                FrameOrKeepAlive::KeepAlive => continue,
+            }
+        }
+    }
+
+    fn value<'a>(frame: &'a Frame, name: &str) -> &'a str {
+        let value = frame.headers.get(name.as_bytes()).expect(name);
+        std::str::from_utf8(value).expect("utf8")
+    }
+
+    async fn open(broker: &Broker, connect: Frame) -> (Peer, Frame) {
+        let (client, server) = duplex(4096);
+        let broker = broker.clone();
+        tokio::spawn(async move { broker.serve(server).await });
+        let mut peer = wrap(client);
+        send(&mut peer, connect).await;
+        let reply = next_frame(&mut peer).await;
+        (peer, reply)
+    }
+
+    async fn session(broker: &Broker) -> Peer {
+        let connect = frame(Command::Connect, &[("accept-version", "1.2")]);
+        let (peer, connected) = open(broker, connect).await;
+        assert_eq!(connected.command, Command::Connected);
+        peer
+    }
+
+    #[tokio::test]
+    async fn refuses_unsupported_versions() {
+        env_logger::try_init().unwrap_or_default();
+        let broker = Broker::new();
+        let connect = frame(Command::Connect, &[("accept-version", "2.0")]);
+        let (mut peer, reply) = open(&broker, connect).await;
+        assert_eq!(reply.command, Command::Error);
+        assert_eq!(value(&reply, "version"), "1.0,1.1,1.2");
+        assert!(peer.next().await.is_none(), "Connection closed");
+    }
+
+    #[tokio::test]
+    async fn speaks_1_0_to_clients_without_accept_version() {
+        env_logger::try_init().unwrap_or_default();
+        let broker = Broker::new().with_heartbeat(Duration::from_millis(10));
+        let (mut peer, connected) = open(&broker, frame(Command::Connect, &[])).await;
+        assert_eq!(connected.command, Command::Connected);
+        assert_eq!(value(&connected, "version"), "1.0");
+        assert_eq!(value(&connected, "heart-beat"), "0,0");
+
+        let subscribe = frame(
+            Command::Subscribe,
+            &[("destination", "/queue/q"), ("id", "s"), ("ack", "client")],
+        );
+        send(&mut peer, subscribe).await;
+        send(
+            &mut peer,
+            frame(Command::Send, &[("destination", "/queue/q")]),
+        )
+        .await;
+        let message = next_frame(&mut peer).await;
+        assert_eq!(message.headers.get("ack".as_bytes()), None);
+
+        let message_id = value(&message, "message-id").to_string();
+        let ack = frame(
+            Command::Ack,
+            &[("message-id", &message_id), ("receipt", "r")],
+        );
+        send(&mut peer, ack).await;
+        assert_eq!(next_frame(&mut peer).await.command, Command::Receipt);
+
+        let nack = frame(Command::Nack, &[("message-id", &message_id)]);
+        send(&mut peer, nack).await;
+        let error = next_frame(&mut peer).await;
+        assert_eq!(error.command, Command::Error);
+        assert_eq!(
+            value(&error, "message"),
+            "NACK is not supported by STOMP 1.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn topics_deliver_to_every_subscriber() {
+        env_logger::try_init().unwrap_or_default();
+        let broker = Broker::new();
+        let mut a = session(&broker).await;
+        let mut b = session(&broker).await;
+        for peer in [&mut a, &mut b].iter_mut() {
+            let subscribe = frame(
+                Command::Subscribe,
+                &[("destination", "/topic/t"), ("id", "s"), ("receipt", "r")],
+            );
+            send(peer, subscribe).await;
+            assert_eq!(next_frame(peer).await.command, Command::Receipt);
+        }
+
+        send(&mut a, frame(Command::Send, &[("destination", "/topic/t")])).await;
+
+        for peer in [&mut a, &mut b].iter_mut() {
+            let message = next_frame(peer).await;
+            assert_eq!(message.command, Command::Message);
+            assert_eq!(value(&message, "destination"), "/topic/t");
+            assert_eq!(value(&message, "subscription"), "s");
+        }
+    }
+
+    #[tokio::test]
+    async fn client_acks_are_cumulative() {
+        env_logger::try_init().unwrap_or_default();
+        let broker = Broker::new();
+        let mut peer = session(&broker).await;
+        for _ in 0..3 {
+            send(
+                &mut peer,
+                frame(Command::Send, &[("destination", "/queue/q")]),
+            )
+            .await;
+        }
+        let subscribe = frame(
+            Command::Subscribe,
+            &[("destination", "/queue/q"), ("id", "s"), ("ack", "client")],
+        );
+        send(&mut peer, subscribe).await;
+        let mut acks = Vec::new();
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let message = next_frame(&mut peer).await;
+            acks.push(value(&message, "ack").to_string());
+            ids.push(value(&message, "message-id").to_string());
+        }
+
+        let ack = frame(Command::Ack, &[("id", &acks[1]), ("receipt", "r")]);
+        send(&mut peer, ack).await;
+        assert_eq!(next_frame(&mut peer).await.command, Command::Receipt);
+
+        // The first message went with the second; only the third remains.
+        let ack = frame(Command::Ack, &[("id", &acks[0]), ("receipt", "r")]);
+        send(&mut peer, ack).await;
+        let error = next_frame(&mut peer).await;
+        assert_eq!(error.command, Command::Error);
+        assert_eq!(value(&error, "receipt-id"), "r");
+        drop(peer);
+
+        let mut peer = session(&broker).await;
+        let subscribe = frame(
+            Command::Subscribe,
+            &[("destination", "/queue/q"), ("id", "s")],
+        );
+        send(&mut peer, subscribe).await;
+        let message = next_frame(&mut peer).await;
+        assert_eq!(value(&message, "message-id"), ids[2]);
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_transactions() {
+        env_logger::try_init().unwrap_or_default();
+        let broker = Broker::new();
+        let mut peer = session(&broker).await;
+        let commit = frame(Command::Commit, &[("transaction", "tx"), ("receipt", "r")]);
+        send(&mut peer, commit).await;
+
+        let error = next_frame(&mut peer).await;
+        assert_eq!(error.command, Command::Error);
+        assert_eq!(value(&error, "message"), "Unknown transaction");
+        assert_eq!(value(&error, "receipt-id"), "r");
+        assert!(peer.next().await.is_none(), "Connection closed");
+    }
+
+    #[tokio::test]
+    async fn commits_all_of_a_transaction_or_none_of_it() {
+        env_logger::try_init().unwrap_or_default();
+        let broker = Broker::new();
+        let mut watcher = session(&broker).await;
+        let subscribe = frame(
+            Command::Subscribe,
+            &[("destination", "/topic/t"), ("id", "s"), ("receipt", "r")],
+        );
+        send(&mut watcher, subscribe).await;
+        assert_eq!(next_frame(&mut watcher).await.command, Command::Receipt);
+
+        let mut peer = session(&broker).await;
+        send(&mut peer, frame(Command::Begin, &[("transaction", "tx")])).await;
+        let publish = frame(
+            Command::Send,
+            &[("destination", "/topic/t"), ("transaction", "tx")],
+        );
+        send(&mut peer, publish).await;
+        let ack = frame(Command::Ack, &[("id", "unknown"), ("transaction", "tx")]);
+        send(&mut peer, ack).await;
+        let commit = frame(Command::Commit, &[("transaction", "tx"), ("receipt", "r")]);
+        send(&mut peer, commit).await;
+        let error = next_frame(&mut peer).await;
+        assert_eq!(error.command, Command::Error);
+        assert_eq!(value(&error, "message"), "Unknown ack id");
+
+        // The transaction's SEND was never published.
+        let publish = frame(Command::Send, &[("destination", "/topic/t"), ("n", "2")]);
+        send(&mut watcher, publish).await;
+        let message = next_frame(&mut watcher).await;
+        assert_eq!(value(&message, "n"), "2");
+    }
+
+    #[tokio::test]
+    async fn sends_negotiated_heartbeats() {
+        env_logger::try_init().unwrap_or_default();
+        let broker = Broker::new().with_heartbeat(Duration::from_millis(10));
+        let connect = frame(
+            Command::Connect,
+            &[("accept-version", "1.2"), ("heart-beat", "0,20")],
+        );
+        let (mut peer, connected) = open(&broker, connect).await;
+        assert_eq!(value(&connected, "heart-beat"), "10,10");
+
+        let it = peer.next().await.expect("item").expect("decode");
+        assert_eq!(it, FrameOrKeepAlive::KeepAlive);
+    }
+}
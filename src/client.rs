@@ -1,106 +1,1266 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
 use std::task::{Context, Poll};
 use std::time::Duration;
 
-use futures::channel::{
-    mpsc::{channel, Receiver, Sender},
-    oneshot,
-};
-use futures::{sink::SinkExt, stream::Stream};
+use futures::channel::{mpsc::Sender, oneshot};
+use futures::{lock::Mutex as AsyncMutex, ready, sink::SinkExt, stream::Stream};
 use log::*;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpStream, ToSocketAddrs};
 
 use crate::connection::{
-    self, AckReq, ClientReq, ConnectReq, Connection, DisconnectReq, PublishReq, SubscribeReq,
+    next_id, receipt_req, AckReq, ClientReq, Connection, DisconnectReq, NackReq, PublishReq,
+    ReceiptReq, SessionInfo, SubscribeReq, TransactionReq, UnsubscribeReq,
 };
 use crate::errors::*;
-use crate::protocol::{AckMode, Frame, Headers};
+use crate::inbox::{inbox, Messages};
+use crate::message::Message;
+use crate::options::{host_of, ConnectOptions, Overflow, SubscribeOptions};
+use crate::protocol::{AckMode, Command, Frame, Headers, Version};
 
-#[derive(Debug)]
+/// A handle to a connection. Clones share the connection, which sends a
+/// `DISCONNECT` once every clone and subscription has been dropped.
+#[derive(Clone, Debug)]
 pub struct Client {
-    c2s: Sender<ClientReq>,
+    pub(crate) c2s: Requests,
+    pub(crate) info: SessionInfo,
+    pub(crate) subscription_buffer: usize,
 }
 
 #[derive(Debug)]
 pub struct Subscription {
-    s2c: Receiver<Frame>,
+    s2c: Messages,
+    c2s: Requests,
+    id: Vec<u8>,
+    // Lent to received messages, which should not keep the connection open.
+    acks: Arc<Requests>,
+    version: Version,
+    ack_mode: AckMode,
+    unsubscribed: bool,
+}
+
+/// A message delivered to a `Subscription`, which can acknowledge itself
+/// for as long as the subscription is open.
+#[derive(Debug)]
+pub struct ReceivedMessage {
+    frame: Frame,
+    c2s: Weak<Requests>,
+    version: Version,
+    ack_mode: AckMode,
+}
+
+/// A STOMP transaction. Every frame sent through it carries the
+/// `transaction` header; dropping it without calling `commit` sends an
+/// `ABORT`.
+#[derive(Debug)]
+pub struct Transaction {
+    c2s: Requests,
+    version: Version,
+    id: Vec<u8>,
+    finished: bool,
+}
+
+// Every handle to a connection queues its requests through one sender,
+// taking turns, as each clone of a sender may queue a request of its own
+// beyond the connection's `request_buffer`.
+#[derive(Clone, Debug)]
+pub(crate) struct Requests {
+    sender: Arc<AsyncMutex<Sender<ClientReq>>>,
+    // For drop handlers, which cannot wait their turn.
+    spare: Sender<ClientReq>,
+    // Set by a reconnecting connection while it rejects requests offline.
+    rejecting: Arc<AtomicBool>,
 }
 
-pub async fn connect<A: ToSocketAddrs>(
+/// Connects to the broker at `a`. See `Client::builder` for more options.
+///
+/// The `host` header, naming the virtual host, defaults to the host named by
+/// the address, eg: `broker.example` for `"broker.example:61613"`. Bare
+/// socket addresses name no host, so the header is left out, and the broker
+/// uses its default; pass a `host` header, or use
+/// `ConnectOptions::virtual_host`, to choose one.
+pub async fn connect<A: BrokerAddr>(
     a: A,
     credentials: Option<(&str, &str)>,
     keepalive: Option<Duration>,
     headers: Headers,
 ) -> Result<(Connection, Client)> {
+    let options = match a.host() {
+        Some(host) => options(credentials, keepalive, headers).virtual_host(host),
+        None => options(credentials, keepalive, headers).without_default_virtual_host(),
+    };
     let conn = TcpStream::connect(a).await?;
+    options.connect_with_transport(conn).await
+}
 
-    let req = ConnectReq {
-        credentials: credentials.map(|(u, p)| (u.to_string(), p.to_string())),
-        keepalive,
-        headers,
-    };
+/// As `connect`, but speaks STOMP over an already established stream, such
+/// as a Unix socket, a tunnel, or an in-memory pipe. See
+/// `ConnectOptions::connect_with_transport` for more options.
+///
+/// The `host` header defaults to `localhost`.
+pub async fn connect_with_transport<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    transport: T,
+    credentials: Option<(&str, &str)>,
+    keepalive: Option<Duration>,
+    headers: Headers,
+) -> Result<(Connection, Client)> {
+    options(credentials, keepalive, headers)
+        .connect_with_transport(transport)
+        .await
+}
+
+// The options the `connect` family of functions take as arguments.
+pub(crate) fn options(
+    credentials: Option<(&str, &str)>,
+    keepalive: Option<Duration>,
+    headers: Headers,
+) -> ConnectOptions {
+    let mut options = Client::builder().headers(headers);
+    if let Some((user, pass)) = credentials {
+        options = options.credentials(user, pass);
+    }
+    if let Some(keepalive) = keepalive {
+        options = options
+            .send_heartbeat(keepalive)
+            .receive_heartbeat(keepalive);
+    }
+    options
+}
 
-    let (mux, c2s_tx) = connection::connect(conn, req).await?;
+/// An address `connect` can dial: anything tokio can resolve, which may also
+/// name the broker's host.
+pub trait BrokerAddr: ToSocketAddrs {
+    /// The host named by the address, if any, for the `host` header.
+    fn host(&self) -> Option<&str> {
+        None
+    }
+}
 
-    let client = Client { c2s: c2s_tx };
-    Ok((mux, client))
+impl BrokerAddr for str {
+    fn host(&self) -> Option<&str> {
+        Some(host_of(self))
+    }
+}
+
+impl BrokerAddr for String {
+    fn host(&self) -> Option<&str> {
+        Some(host_of(self))
+    }
+}
+
+impl BrokerAddr for (&str, u16) {
+    fn host(&self) -> Option<&str> {
+        Some(self.0)
+    }
+}
+
+impl BrokerAddr for (String, u16) {
+    fn host(&self) -> Option<&str> {
+        Some(&self.0)
+    }
+}
+
+impl BrokerAddr for SocketAddr {}
+impl BrokerAddr for SocketAddrV4 {}
+impl BrokerAddr for SocketAddrV6 {}
+impl BrokerAddr for (IpAddr, u16) {}
+impl BrokerAddr for (Ipv4Addr, u16) {}
+impl BrokerAddr for (Ipv6Addr, u16) {}
+impl BrokerAddr for &[SocketAddr] {}
+
+impl<T: BrokerAddr + ?Sized> BrokerAddr for &T {
+    fn host(&self) -> Option<&str> {
+        (**self).host()
+    }
+}
+
+fn ack_header(headers: &Headers, version: Version) -> Result<Vec<u8>> {
+    headers
+        .get(version.ack_header().as_bytes())
+        .map(|v| v.to_vec())
+        .ok_or(StompError::NoAckHeader)
+}
+
+fn subscription_header(headers: &Headers) -> Option<Vec<u8>> {
+    headers.get("subscription".as_bytes()).cloned()
+}
+
+// The header that limits how many unacknowledged messages the server sends
+// to a subscription, for servers known to support one.
+fn prefetch_header(server: Option<&str>) -> Option<&'static str> {
+    let server = server?.to_ascii_lowercase();
+    // Artemis limits its consumers by bytes instead.
+    if server.starts_with("activemq") && !server.starts_with("activemq-artemis") {
+        Some("activemq.prefetchSize")
+    } else if server.starts_with("rabbitmq") {
+        Some("prefetch-count")
+    } else {
+        None
+    }
+}
+
+fn check_nack(version: Version) -> Result<()> {
+    if version.supports_nack() {
+        Ok(())
+    } else {
+        Err(StompError::NotSupported("NACK", version))
+    }
+}
+
+impl Requests {
+    pub(crate) fn new(sender: Sender<ClientReq>) -> Self {
+        Self::rejecting(sender, Arc::default())
+    }
+
+    pub(crate) fn rejecting(sender: Sender<ClientReq>, rejecting: Arc<AtomicBool>) -> Self {
+        Requests {
+            spare: sender.clone(),
+            sender: Arc::new(AsyncMutex::new(sender)),
+            rejecting,
+        }
+    }
+
+    pub(crate) async fn send(&self, req: ClientReq) -> Result<()> {
+        if self.rejecting.load(Ordering::SeqCst) {
+            match req {
+                ClientReq::Disconnect(_) | ClientReq::Subscribe(_) | ClientReq::Unsubscribe(_) => {}
+                _ => return Err(StompError::Disconnected),
+            }
+        }
+        self.sender.lock().await.send(req).await?;
+        Ok(())
+    }
+
+    // Queues a request without waiting. A fresh clone of the sender may
+    // always queue one, so this only fails once the connection is gone.
+    fn send_now(&self, req: ClientReq) -> Result<()> {
+        self.spare
+            .clone()
+            .try_send(req)
+            .map_err(|e| e.into_send_error().into())
+    }
 }
 
 impl Client {
+    pub(crate) fn new(c2s: Sender<ClientReq>, conn: &Connection) -> Self {
+        Client {
+            c2s: Requests::new(c2s),
+            info: conn.session_info().clone(),
+            subscription_buffer: 0,
+        }
+    }
+
+    /// Starts building the options for a connection.
+    pub fn builder() -> ConnectOptions {
+        ConnectOptions::default()
+    }
+
+    /// The protocol version negotiated with the server.
+    pub fn version(&self) -> Version {
+        self.info.version()
+    }
+
+    /// The `server` header sent by the server, eg: `RabbitMQ/3.7.8`.
+    pub fn server(&self) -> Option<&str> {
+        self.info.server()
+    }
+
+    /// The `session` header sent by the server.
+    pub fn session(&self) -> Option<&str> {
+        self.info.session()
+    }
+
+    pub fn session_info(&self) -> &SessionInfo {
+        &self.info
+    }
+
     pub async fn subscribe(
-        &mut self,
+        &self,
         destination: &str,
         id: &str,
         mode: AckMode,
         headers: Headers,
     ) -> Result<Subscription> {
-        let (tx, rx) = channel(0);
+        let options = SubscribeOptions::new(mode).headers(headers);
+        self.send_subscribe(destination, id, options, None).await
+    }
+
+    /// As `subscribe`, but waits for the server to confirm the subscription
+    /// with a `RECEIPT`.
+    pub async fn subscribe_with_receipt(
+        &self,
+        destination: &str,
+        id: &str,
+        mode: AckMode,
+        headers: Headers,
+    ) -> Result<Subscription> {
+        let options = SubscribeOptions::new(mode).headers(headers);
+        let (receipt, rx) = receipt_req();
+        let sub = self
+            .send_subscribe(destination, id, options, Some(receipt))
+            .await?;
+        rx.await??;
+        Ok(sub)
+    }
+
+    /// As `subscribe`, but with control over how messages are buffered for
+    /// the subscription; see `SubscribeOptions`.
+    pub async fn subscribe_with(
+        &self,
+        destination: &str,
+        id: &str,
+        options: SubscribeOptions,
+    ) -> Result<Subscription> {
+        self.send_subscribe(destination, id, options, None).await
+    }
+
+    async fn send_subscribe(
+        &self,
+        destination: &str,
+        id: &str,
+        options: SubscribeOptions,
+        receipt: Option<ReceiptReq>,
+    ) -> Result<Subscription> {
+        if options.overflow == Overflow::Drop && options.ack_mode == AckMode::Client {
+            return Err(StompError::CumulativeDrop);
+        }
+        let capacity = options.buffer.unwrap_or(self.subscription_buffer);
+        let (tx, rx) = inbox(capacity, options.overflow);
+        let mut headers = options.headers;
+        if let Some(prefetch) = prefetch_header(self.server()) {
+            if capacity > 0 && !headers.contains_key(prefetch.as_bytes()) {
+                headers.insert(
+                    prefetch.as_bytes().to_vec(),
+                    capacity.to_string().into_bytes(),
+                );
+            }
+        }
+        let id = id.as_bytes().to_vec();
         let req = SubscribeReq {
             destination: destination.to_string(),
-            id: id.as_bytes().to_vec(),
-            ack_mode: mode,
+            id: id.clone(),
+            ack_mode: options.ack_mode.clone(),
             messages: tx,
             headers,
+            receipt,
         };
-        self.c2s.send(ClientReq::Subscribe(req)).await?;
-        Ok(Subscription { s2c: rx })
+        self.request(ClientReq::Subscribe(req)).await?;
+        Ok(Subscription {
+            s2c: rx,
+            c2s: self.c2s.clone(),
+            acks: Arc::new(self.c2s.clone()),
+            id,
+            version: self.version(),
+            ack_mode: options.ack_mode,
+            unsubscribed: false,
+        })
+    }
+
+    pub async fn publish(&self, destination: &str, body: &[u8]) -> Result<()> {
+        self.send(Message::new(destination, body)).await
     }
-    pub async fn publish(&mut self, destination: &str, body: &[u8]) -> Result<()> {
+
+    /// As `publish`, but waits for the server to confirm receipt of the
+    /// message.
+    pub async fn publish_with_receipt(&self, destination: &str, body: &[u8]) -> Result<()> {
+        self.send_with_receipt(Message::new(destination, body))
+            .await
+    }
+
+    pub async fn send(&self, message: Message) -> Result<()> {
+        self.send_publish(message, None).await
+    }
+
+    /// As `send`, but waits for the server to confirm receipt of the
+    /// message.
+    pub async fn send_with_receipt(&self, message: Message) -> Result<()> {
+        let (receipt, rx) = receipt_req();
+        self.send_publish(message, Some(receipt)).await?;
+        rx.await?
+    }
+
+    async fn send_publish(&self, message: Message, receipt: Option<ReceiptReq>) -> Result<()> {
         let req = PublishReq {
-            destination: destination.to_string(),
-            body: body.to_vec(),
+            message,
+            transaction: None,
+            receipt,
         };
-        self.c2s.send(ClientReq::Publish(req)).await?;
+        self.request(ClientReq::Publish(req)).await?;
         trace!("Published frame");
         Ok(())
     }
 
-    pub async fn disconnect(mut self) -> Result<()> {
-        let (done, rx) = oneshot::channel();
-        let id = "42".as_bytes().to_vec();
+    pub async fn disconnect(self) -> Result<()> {
+        let (receipt, rx) = receipt_req();
 
-        let req = DisconnectReq { done, id };
-        self.c2s.send(ClientReq::Disconnect(req)).await?;
+        let req = DisconnectReq { receipt };
+        self.request(ClientReq::Disconnect(req)).await?;
 
-        rx.await?;
+        rx.await??;
 
         Ok(())
     }
 
-    pub async fn ack(&mut self, headers: &Headers) -> Result<()> {
-        let message_id = headers
-            .get("ack".as_bytes())
-            .map(|v| v.to_vec())
-            .ok_or(StompError::NoAckHeader)?;
-        let req = AckReq { message_id };
+    /// Acknowledges the message with these headers. On a subscription in
+    /// `AckMode::Client` this also acknowledges every earlier message; on one
+    /// in `AckMode::Auto` it fails with `StompError::AutoAcknowledged`.
+    pub async fn ack(&self, headers: &Headers) -> Result<()> {
+        self.send_ack(headers, false, None).await
+    }
+
+    /// As `ack`, but waits for the server to confirm the acknowledgement.
+    pub async fn ack_with_receipt(&self, headers: &Headers) -> Result<()> {
+        let (receipt, rx) = receipt_req();
+        self.send_ack(headers, false, Some(receipt)).await?;
+        rx.await?
+    }
+
+    /// Acknowledges the message with these headers and every earlier message
+    /// on its subscription that is still outstanding, whatever the
+    /// subscription's ack mode.
+    pub async fn ack_up_to(&self, headers: &Headers) -> Result<()> {
+        self.send_ack(headers, true, None).await
+    }
+
+    async fn send_ack(
+        &self,
+        headers: &Headers,
+        up_to: bool,
+        receipt: Option<ReceiptReq>,
+    ) -> Result<()> {
+        let (settled, rx) = oneshot::channel();
+        let req = AckReq {
+            message_id: ack_header(headers, self.version())?,
+            subscription: subscription_header(headers),
+            transaction: None,
+            up_to,
+            settled,
+            receipt,
+        };
+        self.request(ClientReq::Ack(req)).await?;
+        rx.await?
+    }
+
+    pub async fn nack(&self, headers: &Headers) -> Result<()> {
+        self.send_nack(headers, None).await
+    }
+
+    /// As `nack`, but waits for the server to confirm the negative
+    /// acknowledgement.
+    pub async fn nack_with_receipt(&self, headers: &Headers) -> Result<()> {
+        let (receipt, rx) = receipt_req();
+        self.send_nack(headers, Some(receipt)).await?;
+        rx.await?
+    }
+
+    async fn send_nack(&self, headers: &Headers, receipt: Option<ReceiptReq>) -> Result<()> {
+        check_nack(self.version())?;
+        let (settled, rx) = oneshot::channel();
+        let req = NackReq {
+            message_id: ack_header(headers, self.version())?,
+            subscription: subscription_header(headers),
+            transaction: None,
+            settled,
+            receipt,
+        };
+        self.request(ClientReq::Nack(req)).await?;
+        rx.await?
+    }
+
+    /// Starts a new transaction on this connection.
+    pub async fn begin(&self) -> Result<Transaction> {
+        let id = next_id("tx");
+        let req = TransactionReq {
+            id: id.clone(),
+            receipt: None,
+        };
+        self.request(ClientReq::Begin(req)).await?;
+        trace!("Began transaction: {:?}", String::from_utf8_lossy(&id));
+        Ok(Transaction {
+            c2s: self.c2s.clone(),
+            version: self.version(),
+            id,
+            finished: false,
+        })
+    }
+
+    async fn request(&self, req: ClientReq) -> Result<()> {
+        self.c2s.send(req).await
+    }
+}
+
+impl Transaction {
+    pub async fn publish(&self, destination: &str, body: &[u8]) -> Result<()> {
+        self.send(Message::new(destination, body)).await
+    }
+
+    pub async fn send(&self, message: Message) -> Result<()> {
+        let req = PublishReq {
+            message,
+            transaction: Some(self.id.clone()),
+            receipt: None,
+        };
+        self.c2s.send(ClientReq::Publish(req)).await
+    }
+
+    pub async fn ack(&self, headers: &Headers) -> Result<()> {
+        let (settled, rx) = oneshot::channel();
+        let req = AckReq {
+            message_id: ack_header(headers, self.version)?,
+            subscription: subscription_header(headers),
+            transaction: Some(self.id.clone()),
+            up_to: false,
+            settled,
+            receipt: None,
+        };
         self.c2s.send(ClientReq::Ack(req)).await?;
+        rx.await?
+    }
+
+    pub async fn nack(&self, headers: &Headers) -> Result<()> {
+        check_nack(self.version)?;
+        let (settled, rx) = oneshot::channel();
+        let req = NackReq {
+            message_id: ack_header(headers, self.version)?,
+            subscription: subscription_header(headers),
+            transaction: Some(self.id.clone()),
+            settled,
+            receipt: None,
+        };
+        self.c2s.send(ClientReq::Nack(req)).await?;
+        rx.await?
+    }
+
+    pub async fn commit(self) -> Result<()> {
+        self.finish(Command::Commit, None).await
+    }
+
+    /// As `commit`, but waits for the server to confirm the commit.
+    pub async fn commit_with_receipt(self) -> Result<()> {
+        let (receipt, rx) = receipt_req();
+        self.finish(Command::Commit, Some(receipt)).await?;
+        rx.await?
+    }
+
+    pub async fn abort(self) -> Result<()> {
+        self.finish(Command::Abort, None).await
+    }
+
+    async fn finish(mut self, command: Command, receipt: Option<ReceiptReq>) -> Result<()> {
+        self.finished = true;
+        let req = TransactionReq {
+            id: self.id.clone(),
+            receipt,
+        };
+        let req = if command == Command::Commit {
+            ClientReq::Commit(req)
+        } else {
+            ClientReq::Abort(req)
+        };
+        self.c2s.send(req).await?;
         Ok(())
     }
 }
 
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        debug!(
+            "Transaction dropped without commit; aborting: {:?}",
+            String::from_utf8_lossy(&self.id)
+        );
+        let req = TransactionReq {
+            id: self.id.clone(),
+            receipt: None,
+        };
+        if let Err(e) = self.c2s.send_now(ClientReq::Abort(req)) {
+            warn!("Could not abort dropped transaction: {:?}", e);
+        }
+    }
+}
+
+impl Subscription {
+    /// Sends an `UNSUBSCRIBE` for this subscription. Dropping the
+    /// subscription has the same effect, but cannot report failure.
+    pub async fn unsubscribe(self) -> Result<()> {
+        self.send_unsubscribe(None).await
+    }
+
+    /// As `unsubscribe`, but waits for the server to confirm the
+    /// `UNSUBSCRIBE`.
+    pub async fn unsubscribe_with_receipt(self) -> Result<()> {
+        let (receipt, rx) = receipt_req();
+        self.send_unsubscribe(Some(receipt)).await?;
+        rx.await?
+    }
+
+    async fn send_unsubscribe(mut self, receipt: Option<ReceiptReq>) -> Result<()> {
+        self.unsubscribed = true;
+        let req = UnsubscribeReq {
+            id: self.id.clone(),
+            receipt,
+        };
+        self.c2s.send(ClientReq::Unsubscribe(req)).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if self.unsubscribed {
+            return;
+        }
+        debug!(
+            "Subscription dropped; unsubscribing: {:?}",
+            String::from_utf8_lossy(&self.id)
+        );
+        let req = UnsubscribeReq {
+            id: self.id.clone(),
+            receipt: None,
+        };
+        if let Err(e) = self.c2s.send_now(ClientReq::Unsubscribe(req)) {
+            debug!("Could not unsubscribe dropped subscription: {:?}", e);
+        }
+    }
+}
+
 impl Stream for Subscription {
-    type Item = Frame;
+    type Item = ReceivedMessage;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.s2c).poll_next(cx)
+        let frame = ready!(Pin::new(&mut self.s2c).poll_next(cx));
+        Poll::Ready(frame.map(|frame| ReceivedMessage {
+            frame,
+            c2s: Arc::downgrade(&self.acks),
+            version: self.version,
+            ack_mode: self.ack_mode.clone(),
+        }))
+    }
+}
+
+impl ReceivedMessage {
+    pub fn destination(&self) -> Option<&str> {
+        self.header_str("destination")
+    }
+
+    pub fn message_id(&self) -> Option<&str> {
+        self.header_str("message-id")
+    }
+
+    pub fn subscription(&self) -> Option<&str> {
+        self.header_str("subscription")
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header_str("content-type")
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.frame.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.frame.body
+    }
+
+    pub fn into_body(self) -> Vec<u8> {
+        self.frame.body
+    }
+
+    /// The ack mode of the subscription this message arrived on.
+    pub fn ack_mode(&self) -> &AckMode {
+        &self.ack_mode
+    }
+
+    /// Acknowledges this message, and in `AckMode::Client` every earlier one
+    /// on the subscription. Fails with `StompError::AutoAcknowledged` on a
+    /// subscription in `AckMode::Auto`.
+    pub async fn ack(&self) -> Result<()> {
+        self.send_ack(false, None).await
+    }
+
+    /// As `ack`, but waits for the server to confirm the acknowledgement.
+    pub async fn ack_with_receipt(&self) -> Result<()> {
+        let (receipt, rx) = receipt_req();
+        self.send_ack(false, Some(receipt)).await?;
+        rx.await?
+    }
+
+    /// Acknowledges this message and every earlier one on the subscription
+    /// that is still outstanding.
+    pub async fn ack_up_to(&self) -> Result<()> {
+        self.send_ack(true, None).await
+    }
+
+    /// Tells the server that this message was not consumed. Fails as `ack`
+    /// does on a subscription in `AckMode::Auto`.
+    pub async fn nack(&self) -> Result<()> {
+        self.check_acknowledgeable()?;
+        check_nack(self.version)?;
+        let (settled, rx) = oneshot::channel();
+        let req = NackReq {
+            message_id: ack_header(&self.frame.headers, self.version)?,
+            subscription: subscription_header(&self.frame.headers),
+            transaction: None,
+            settled,
+            receipt: None,
+        };
+        self.connection()?.send(ClientReq::Nack(req)).await?;
+        rx.await?
+    }
+
+    async fn send_ack(&self, up_to: bool, receipt: Option<ReceiptReq>) -> Result<()> {
+        self.check_acknowledgeable()?;
+        let (settled, rx) = oneshot::channel();
+        let req = AckReq {
+            message_id: ack_header(&self.frame.headers, self.version)?,
+            subscription: subscription_header(&self.frame.headers),
+            transaction: None,
+            up_to,
+            settled,
+            receipt,
+        };
+        self.connection()?.send(ClientReq::Ack(req)).await?;
+        rx.await?
+    }
+
+    fn connection(&self) -> Result<Requests> {
+        let c2s = self.c2s.upgrade().ok_or(StompError::Disconnected)?;
+        Ok((*c2s).clone())
+    }
+
+    fn check_acknowledgeable(&self) -> Result<()> {
+        if self.ack_mode == AckMode::Auto {
+            return Err(StompError::AutoAcknowledged);
+        }
+        Ok(())
+    }
+
+    fn header_str(&self, name: &str) -> Option<&str> {
+        self.frame
+            .headers
+            .get(name.as_bytes())
+            .and_then(|v| std::str::from_utf8(v).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream::StreamExt;
+    use maplit::btreemap;
+    use tokio::net::UnixStream;
+    use tokio::task::JoinHandle;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::connection::{self, wrap, ConnectReq, StompCodec};
+    use crate::protocol::FrameOrKeepAlive;
+
+    async fn connected() -> (
+        Client,
+        Framed<UnixStream, StompCodec>,
+        JoinHandle<Result<()>>,
+    ) {
+        let (a, b) = UnixStream::pair().expect("socket pair");
+        let mut server = wrap(b);
+        let req = ConnectReq::new(None, None, Headers::new());
+        let server_side = async {
+            let frame = next_frame(&mut server).await;
+            assert_eq!(frame.command, Command::Connect);
+            let connected = Frame {
+                command: Command::Connected,
+                headers: btreemap! {
+                    "version".as_bytes().to_vec() => "1.2".as_bytes().to_vec(),
+                    "server".as_bytes().to_vec() => "test/1.0".as_bytes().to_vec(),
+                    "session".as_bytes().to_vec() => "s-1".as_bytes().to_vec(),
+                    "heart-beat".as_bytes().to_vec() => "0,0".as_bytes().to_vec(),
+                    "x-cluster".as_bytes().to_vec() => "east".as_bytes().to_vec(),
+                },
+                body: Vec::new(),
+            };
+            server
+                .send(FrameOrKeepAlive::Frame(connected))
+                .await
+                .expect("send connected");
+        };
+        let (res, ()) = futures::join!(connection::connect(a, req), server_side);
+        let (conn, c2s) = res.expect("connect");
+        let client = Client::new(c2s, &conn);
+        let conn = tokio::spawn(conn);
+        (client, server, conn)
+    }
+
+    async fn next_frame(server: &mut Framed<UnixStream, StompCodec>) -> Frame {
+        server
+            .next()
+            .await
+            .expect("some frame")
+            .expect("frame")
+            .unwrap_frame()
+    }
+
+    fn reply(command: Command, receipt_id: &[u8]) -> FrameOrKeepAlive {
+        FrameOrKeepAlive::Frame(Frame {
+            command,
+            headers: btreemap! {
+                "receipt-id".as_bytes().to_vec() => receipt_id.to_vec(),
+            },
+            body: Vec::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn publish_with_receipt_resolves_on_receipt() {
+        let (client, mut server, _conn) = connected().await;
+
+        let server_side = async {
+            let frame = next_frame(&mut server).await;
+            assert_eq!(frame.command, Command::Send);
+            let receipt = frame.headers["receipt".as_bytes()].clone();
+            server
+                .send(reply(Command::Receipt, &receipt))
+                .await
+                .expect("send receipt");
+        };
+
+        let (res, ()) = futures::join!(client.publish_with_receipt("/queue/a", b"x"), server_side);
+        res.expect("publish_with_receipt");
+    }
+
+    #[tokio::test]
+    async fn publish_with_receipt_fails_on_matching_error() {
+        let (client, mut server, _conn) = connected().await;
+
+        let server_side = async {
+            let frame = next_frame(&mut server).await;
+            let receipt = frame.headers["receipt".as_bytes()].clone();
+            server
+                .send(reply(Command::Error, &receipt))
+                .await
+                .expect("send error");
+        };
+
+        let (res, ()) = futures::join!(client.publish_with_receipt("/queue/a", b"x"), server_side);
+        match res {
+            Err(StompError::StompError(frame)) => assert_eq!(frame.command, Command::Error),
+            other => panic!("Expected server error; got: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_with_receipt_fails_when_connection_drops() {
+        let (client, mut server, _conn) = connected().await;
+
+        let server_side = async {
+            next_frame(&mut server).await;
+            drop(server);
+        };
+
+        let (res, ()) = futures::join!(client.publish_with_receipt("/queue/a", b"x"), server_side);
+        assert!(res.is_err(), "Expected failure; got: {:?}", res);
+    }
+
+    #[tokio::test]
+    async fn subscribe_with_receipt_fails_on_matching_error() {
+        let (client, mut server, _conn) = connected().await;
+
+        let server_side = async {
+            let frame = next_frame(&mut server).await;
+            assert_eq!(frame.command, Command::Subscribe);
+            let receipt = frame.headers["receipt".as_bytes()].clone();
+            server
+                .send(reply(Command::Error, &receipt))
+                .await
+                .expect("send error");
+        };
+
+        let (res, ()) = futures::join!(
+            client.subscribe_with_receipt("/queue/a", "one", AckMode::Auto, Headers::new()),
+            server_side
+        );
+        match res {
+            Err(StompError::StompError(frame)) => assert_eq!(frame.command, Command::Error),
+            other => panic!("Expected server error; got: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn server_errors_end_the_connection() {
+        let (_client, mut server, conn) = connected().await;
+
+        let error = Frame {
+            command: Command::Error,
+            headers: btreemap! {
+                "message".as_bytes().to_vec() => "go away".as_bytes().to_vec(),
+            },
+            body: Vec::new(),
+        };
+        server
+            .send(FrameOrKeepAlive::Frame(error.clone()))
+            .await
+            .expect("send error");
+
+        match conn.await.expect("join") {
+            Err(StompError::StompError(frame)) => assert_eq!(frame, error),
+            other => panic!("Expected server error; got: {:?}", other),
+        }
+    }
+
+    async fn deliver(
+        client: &Client,
+        server: &mut Framed<UnixStream, StompCodec>,
+        mode: AckMode,
+    ) -> (Subscription, ReceivedMessage) {
+        let mut sub = client
+            .subscribe("/queue/a", "sub-1", mode, Headers::new())
+            .await
+            .expect("subscribe");
+        assert_eq!(next_frame(server).await.command, Command::Subscribe);
+        let message = Frame {
+            command: Command::Message,
+            headers: btreemap! {
+                "destination".as_bytes().to_vec() => "/queue/a".as_bytes().to_vec(),
+                "message-id".as_bytes().to_vec() => "m-1".as_bytes().to_vec(),
+                "subscription".as_bytes().to_vec() => "sub-1".as_bytes().to_vec(),
+                "ack".as_bytes().to_vec() => "a-1".as_bytes().to_vec(),
+                "content-type".as_bytes().to_vec() => "text/plain".as_bytes().to_vec(),
+            },
+            body: b"hello".to_vec(),
+        };
+        server
+            .send(FrameOrKeepAlive::Frame(message))
+            .await
+            .expect("send message");
+        let message = sub.next().await.expect("message");
+        (sub, message)
+    }
+
+    #[tokio::test]
+    async fn received_messages_expose_their_headers() {
+        let (client, mut server, _conn) = connected().await;
+
+        let (_sub, message) = deliver(&client, &mut server, AckMode::Auto).await;
+
+        assert_eq!(message.destination(), Some("/queue/a"));
+        assert_eq!(message.message_id(), Some("m-1"));
+        assert_eq!(message.subscription(), Some("sub-1"));
+        assert_eq!(message.content_type(), Some("text/plain"));
+        assert_eq!(message.body(), b"hello");
+        assert_eq!(message.ack_mode(), &AckMode::Auto);
+    }
+
+    #[tokio::test]
+    async fn received_messages_ack_themselves() {
+        let (client, mut server, _conn) = connected().await;
+        let (_sub, message) = deliver(&client, &mut server, AckMode::Client).await;
+
+        message.ack().await.expect("ack");
+
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.command, Command::Ack);
+        assert_eq!(
+            frame.headers.get("id".as_bytes()),
+            Some(&"a-1".as_bytes().to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn acking_auto_acked_messages_fails() {
+        let (client, mut server, _conn) = connected().await;
+        let (_sub, message) = deliver(&client, &mut server, AckMode::Auto).await;
+
+        let res = message.ack().await;
+        assert!(
+            matches!(res, Err(StompError::AutoAcknowledged)),
+            "Expected an error; got: {:?}",
+            res
+        );
+        let res = client.ack(message.headers()).await;
+        assert!(
+            matches!(res, Err(StompError::AutoAcknowledged)),
+            "Expected an error; got: {:?}",
+            res
+        );
+    }
+
+    #[tokio::test]
+    async fn received_messages_cannot_ack_once_unsubscribed() {
+        let (client, mut server, _conn) = connected().await;
+        let (sub, message) = deliver(&client, &mut server, AckMode::Client).await;
+
+        drop(sub);
+
+        let res = message.ack().await;
+        assert!(
+            matches!(res, Err(StompError::Disconnected)),
+            "Expected an error; got: {:?}",
+            res
+        );
+    }
+
+    #[tokio::test]
+    async fn exposes_connected_headers() {
+        let (client, _server, _conn) = connected().await;
+
+        assert_eq!(client.version(), Version::V1_2);
+        assert_eq!(client.server(), Some("test/1.0"));
+        assert_eq!(client.session(), Some("s-1"));
+
+        let info = client.session_info();
+        assert_eq!(info.send_heartbeat(), None);
+        assert_eq!(info.receive_heartbeat(), None);
+        assert_eq!(
+            info.headers(),
+            &btreemap! {
+                "x-cluster".as_bytes().to_vec() => "east".as_bytes().to_vec(),
+            }
+        );
+    }
+
+    fn message_for(subscription: &str, n: usize) -> FrameOrKeepAlive {
+        FrameOrKeepAlive::Frame(Frame {
+            command: Command::Message,
+            headers: btreemap! {
+                "message-id".as_bytes().to_vec() => format!("m-{}", n).into_bytes(),
+                "subscription".as_bytes().to_vec() => subscription.as_bytes().to_vec(),
+                "ack".as_bytes().to_vec() => format!("a-{}", n).into_bytes(),
+            },
+            body: n.to_string().into_bytes(),
+        })
+    }
+
+    // Publishes with a receipt, which the server sends once the frames
+    // before it have been read.
+    async fn acknowledged_publish(client: &Client, server: &mut Framed<UnixStream, StompCodec>) {
+        let server_side = async {
+            let frame = next_frame(server).await;
+            let receipt = frame.headers["receipt".as_bytes()].clone();
+            server
+                .send(reply(Command::Receipt, &receipt))
+                .await
+                .expect("send receipt");
+        };
+        let (res, ()) = futures::join!(client.publish_with_receipt("/queue/c", b"x"), server_side);
+        res.expect("publish_with_receipt");
+    }
+
+    #[tokio::test]
+    async fn full_subscriptions_do_not_hold_up_others() {
+        let (client, mut server, _conn) = connected().await;
+        let options = SubscribeOptions::default().overflow(Overflow::Queue(2));
+        let mut slow = client
+            .subscribe_with("/queue/a", "sub-1", options)
+            .await
+            .expect("subscribe");
+        assert_eq!(next_frame(&mut server).await.command, Command::Subscribe);
+        let mut fast = client
+            .subscribe("/queue/b", "sub-2", AckMode::Auto, Headers::new())
+            .await
+            .expect("subscribe");
+        assert_eq!(next_frame(&mut server).await.command, Command::Subscribe);
+
+        for n in 0..3 {
+            server.send(message_for("sub-1", n)).await.expect("send");
+        }
+        server.send(message_for("sub-2", 3)).await.expect("send");
+        assert_eq!(fast.next().await.expect("message").body(), b"3");
+
+        // Receipts are still handled while `slow` has yet to read anything.
+        acknowledged_publish(&client, &mut server).await;
+
+        for n in 0..3 {
+            let message = slow.next().await.expect("message");
+            assert_eq!(message.body(), n.to_string().as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn unread_subscriptions_do_not_hold_up_others_by_default() {
+        let (client, mut server, _conn) = connected().await;
+        let mut slow = client
+            .subscribe("/queue/a", "sub-1", AckMode::Auto, Headers::new())
+            .await
+            .expect("subscribe");
+        assert_eq!(next_frame(&mut server).await.command, Command::Subscribe);
+        let mut fast = client
+            .subscribe("/queue/b", "sub-2", AckMode::Auto, Headers::new())
+            .await
+            .expect("subscribe");
+        assert_eq!(next_frame(&mut server).await.command, Command::Subscribe);
+
+        for n in 0..5 {
+            server.send(message_for("sub-1", n)).await.expect("send");
+        }
+        server.send(message_for("sub-2", 5)).await.expect("send");
+        assert_eq!(fast.next().await.expect("message").body(), b"5");
+        acknowledged_publish(&client, &mut server).await;
+
+        for n in 0..5 {
+            let message = slow.next().await.expect("message");
+            assert_eq!(message.body(), n.to_string().as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn full_subscriptions_can_drop_messages() {
+        let (client, mut server, _conn) = connected().await;
+        let options = SubscribeOptions::new(AckMode::ClientIndividual).overflow(Overflow::Drop);
+        let mut sub = client
+            .subscribe_with("/queue/a", "sub-1", options)
+            .await
+            .expect("subscribe");
+        assert_eq!(next_frame(&mut server).await.command, Command::Subscribe);
+
+        for n in 0..3 {
+            server.send(message_for("sub-1", n)).await.expect("send");
+        }
+        acknowledged_publish(&client, &mut server).await;
+        let first = sub.next().await.expect("message");
+        assert_eq!(first.message_id(), Some("m-0"));
+        server.send(message_for("sub-1", 3)).await.expect("send");
+        let next = sub.next().await.expect("message");
+        assert_eq!(next.message_id(), Some("m-3"));
+
+        // Only the messages delivered are left to acknowledge.
+        next.ack_up_to().await.expect("ack");
+        for ack in &["a-0", "a-3"] {
+            let frame = next_frame(&mut server).await;
+            assert_eq!(frame.command, Command::Ack);
+            assert_eq!(frame.headers["id".as_bytes()], ack.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn cumulative_acks_cannot_drop_messages() {
+        let (client, mut server, _conn) = connected().await;
+        let options = SubscribeOptions::new(AckMode::Client).overflow(Overflow::Drop);
+        let res = client.subscribe_with("/queue/a", "sub-1", options).await;
+        assert!(
+            matches!(res, Err(StompError::CumulativeDrop)),
+            "Expected an error; got: {:?}",
+            res.map(|_| ())
+        );
+
+        // Nothing was subscribed.
+        acknowledged_publish(&client, &mut server).await;
+    }
+
+    #[tokio::test]
+    async fn full_subscriptions_can_fail_the_connection() {
+        let (client, mut server, conn) = connected().await;
+        let options = SubscribeOptions::default().overflow(Overflow::Fail);
+        let _sub = client
+            .subscribe_with("/queue/a", "sub-1", options)
+            .await
+            .expect("subscribe");
+        assert_eq!(next_frame(&mut server).await.command, Command::Subscribe);
+
+        for n in 0..2 {
+            server.send(message_for("sub-1", n)).await.expect("send");
+        }
+
+        let res = conn.await.expect("join");
+        assert!(
+            matches!(res, Err(StompError::SubscriptionOverflow(ref id)) if id == "sub-1"),
+            "Expected an overflow; got: {:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn prefetch_headers_depend_on_the_server() {
+        assert_eq!(
+            prefetch_header(Some("ActiveMQ/5.15.9")),
+            Some("activemq.prefetchSize")
+        );
+        assert_eq!(
+            prefetch_header(Some("RabbitMQ/3.7.8")),
+            Some("prefetch-count")
+        );
+        assert_eq!(prefetch_header(Some("ActiveMQ-Artemis/2.10.1")), None);
+        assert_eq!(prefetch_header(Some("test/1.0")), None);
+        assert_eq!(prefetch_header(None), None);
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_request_buffer() {
+        let (tx, mut rx) = futures::channel::mpsc::channel(1);
+        let requests = Requests::new(tx);
+        let publish = || {
+            ClientReq::Publish(PublishReq {
+                message: Message::new("/queue/a", b"x"),
+                transaction: None,
+                receipt: None,
+            })
+        };
+        requests.clone().send(publish()).await.expect("send");
+        // A fresh clone of the sender would not have to wait.
+        let other = requests.clone();
+        let blocked = other.send(publish());
+        futures::pin_mut!(blocked);
+        assert!(futures::poll!(blocked.as_mut()).is_pending());
+
+        rx.next().await.expect("request");
+        blocked.await.expect("send");
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_connection() {
+        let (client, mut server, _conn) = connected().await;
+
+        let other = client.clone();
+        let publisher = tokio::spawn(async move { other.publish("/queue/a", b"x").await });
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.command, Command::Send);
+        publisher.await.expect("join").expect("publish");
+
+        client.publish("/queue/b", b"y").await.expect("publish");
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.headers["destination".as_bytes()], b"/queue/b");
+    }
+
+    #[tokio::test]
+    async fn disconnects_once_every_handle_is_dropped() {
+        let (client, mut server, conn) = connected().await;
+        let mut sub = client
+            .subscribe("/queue/a", "sub-1", AckMode::Auto, Headers::new())
+            .await
+            .expect("subscribe");
+        assert_eq!(next_frame(&mut server).await.command, Command::Subscribe);
+
+        drop(client.clone());
+        drop(client);
+        // The subscription keeps the connection open.
+        server.send(message_for("sub-1", 0)).await.expect("send");
+        assert!(sub.next().await.is_some());
+
+        drop(sub);
+        assert_eq!(next_frame(&mut server).await.command, Command::Unsubscribe);
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.command, Command::Disconnect);
+        let receipt = frame.headers["receipt".as_bytes()].clone();
+        server
+            .send(reply(Command::Receipt, &receipt))
+            .await
+            .expect("send receipt");
+
+        conn.await.expect("join").expect("connection");
+    }
+
+    #[tokio::test]
+    async fn gives_up_waiting_for_a_disconnect_receipt() {
+        let (client, mut server, conn) = connected().await;
+        drop(client);
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.command, Command::Disconnect);
+        assert!(frame.headers["receipt".as_bytes()].starts_with(b"receipt-"));
+
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_secs(5)).await;
+        conn.await.expect("join").expect("connection");
     }
 }
@@ -1,8 +1,9 @@
 use std::{
     cmp,
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
     future::Future,
     pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
     task::{Context, Poll},
     time::Duration,
 };
@@ -13,7 +14,7 @@ use futures::{
         mpsc::{channel, Receiver, Sender},
         oneshot,
     },
-    future::{BoxFuture, FutureExt, TryFutureExt},
+    future::{BoxFuture, FutureExt},
     lock::BiLock,
     sink::{Sink, SinkExt},
     stream::{Stream, StreamExt},
@@ -21,20 +22,35 @@ use futures::{
 use log::*;
 use maplit::btreemap;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::time::timeout;
+use tokio::time::{timeout, timeout_at, Instant};
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
+use crate::activity::{Activity, Tracked};
 use crate::errors::*;
+use crate::inbox::{Delivery, Inbox};
+use crate::message::Message;
 use crate::parser::parse_frame;
-use crate::protocol::{AckMode, Command, Frame, FrameOrKeepAlive, Headers};
+use crate::protocol::{AckMode, Command, Frame, FrameOrKeepAlive, Headers, Version};
 use crate::unparser::encode_frame;
 
-pub(crate) struct StompCodec;
+pub(crate) struct StompCodec {
+    pub(crate) version: Version,
+}
+
+// Transports whose framing depends on the negotiated protocol version.
+pub(crate) trait SetVersion {
+    fn set_version(&mut self, version: Version);
+}
 
 #[derive(Debug)]
-pub(crate) struct DisconnectReq {
+pub(crate) struct ReceiptReq {
     pub(crate) id: Vec<u8>,
-    pub(crate) done: oneshot::Sender<()>,
+    pub(crate) done: oneshot::Sender<Result<()>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct DisconnectReq {
+    pub(crate) receipt: ReceiptReq,
 }
 
 #[derive(Debug)]
@@ -42,56 +58,250 @@ pub(crate) struct SubscribeReq {
     pub(crate) destination: String,
     pub(crate) id: Vec<u8>,
     pub(crate) ack_mode: AckMode,
-    pub(crate) messages: Sender<Frame>,
+    pub(crate) messages: Inbox,
     pub(crate) headers: Headers,
+    pub(crate) receipt: Option<ReceiptReq>,
+}
+
+#[derive(Debug)]
+pub(crate) struct UnsubscribeReq {
+    pub(crate) id: Vec<u8>,
+    pub(crate) receipt: Option<ReceiptReq>,
 }
 
 #[derive(Debug)]
 pub(crate) struct PublishReq {
-    pub(crate) destination: String,
-    pub(crate) body: Vec<u8>,
+    pub(crate) message: Message,
+    pub(crate) transaction: Option<Vec<u8>>,
+    pub(crate) receipt: Option<ReceiptReq>,
 }
 
 #[derive(Debug)]
 pub(crate) struct AckReq {
     pub(crate) message_id: Vec<u8>,
+    pub(crate) subscription: Option<Vec<u8>>,
+    pub(crate) transaction: Option<Vec<u8>>,
+    // Also acknowledge every earlier message on the subscription.
+    pub(crate) up_to: bool,
+    // Whether the acknowledgement made sense for the subscription.
+    pub(crate) settled: oneshot::Sender<Result<()>>,
+    pub(crate) receipt: Option<ReceiptReq>,
 }
+
+#[derive(Debug)]
+pub(crate) struct NackReq {
+    pub(crate) message_id: Vec<u8>,
+    pub(crate) subscription: Option<Vec<u8>>,
+    pub(crate) transaction: Option<Vec<u8>>,
+    pub(crate) settled: oneshot::Sender<Result<()>>,
+    pub(crate) receipt: Option<ReceiptReq>,
+}
+
+#[derive(Debug)]
+pub(crate) struct TransactionReq {
+    pub(crate) id: Vec<u8>,
+    pub(crate) receipt: Option<ReceiptReq>,
+}
+
 #[derive(Debug)]
 pub(crate) struct ConnectReq {
     pub(crate) credentials: Option<(String, String)>,
-    pub(crate) keepalive: Option<Duration>,
+    pub(crate) send_heartbeat: Option<Duration>,
+    pub(crate) receive_heartbeat: Option<Duration>,
+    pub(crate) virtual_host: Option<String>,
+    pub(crate) versions: Vec<Version>,
     pub(crate) headers: Headers,
+    // The capacity of the channel from client handles to the connection.
+    pub(crate) request_buffer: usize,
+    // How many heartbeat intervals the server may miss before we give up.
+    pub(crate) heartbeat_grace: u32,
 }
 
 #[derive(Debug)]
 pub(crate) enum ClientReq {
     Disconnect(DisconnectReq),
     Subscribe(SubscribeReq),
+    Unsubscribe(UnsubscribeReq),
     Publish(PublishReq),
     Ack(AckReq),
+    Nack(NackReq),
+    Begin(TransactionReq),
+    Commit(TransactionReq),
+    Abort(TransactionReq),
+}
+
+impl ClientReq {
+    pub(crate) fn into_receipt(self) -> Option<ReceiptReq> {
+        match self {
+            ClientReq::Disconnect(req) => Some(req.receipt),
+            ClientReq::Subscribe(req) => req.receipt,
+            ClientReq::Unsubscribe(req) => req.receipt,
+            ClientReq::Publish(req) => req.receipt,
+            ClientReq::Ack(req) => req.receipt,
+            ClientReq::Nack(req) => req.receipt,
+            ClientReq::Begin(req) => req.receipt,
+            ClientReq::Commit(req) => req.receipt,
+            ClientReq::Abort(req) => req.receipt,
+        }
+    }
+}
+
+const LIVENESS_BUFFER: usize = 16;
+pub(crate) const DEFAULT_HEARTBEAT_GRACE: u32 = 2;
+// How long to wait for the server to confirm a `DISCONNECT` sent because
+// every handle to the connection was dropped.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn next_id(prefix: &str) -> Vec<u8> {
+    let n = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    format!("{}-{}", prefix, n).into_bytes()
+}
+
+pub(crate) fn receipt_req() -> (ReceiptReq, oneshot::Receiver<Result<()>>) {
+    let (done, rx) = oneshot::channel();
+    let req = ReceiptReq {
+        id: next_id("receipt"),
+        done,
+    };
+    (req, rx)
 }
 
 #[must_use = "The connection future must be polled to make progress"]
 pub struct Connection {
     s2c: BoxFuture<'static, Result<()>>,
     c2s: BoxFuture<'static, Result<()>>,
+    info: SessionInfo,
+    liveness: Option<Receiver<LivenessEvent>>,
+}
+
+/// Reports on heartbeats from the server; see `Connection::liveness`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LivenessEvent {
+    /// Nothing has been heard from the server for this many consecutive
+    /// heartbeat intervals.
+    Missed(u32),
+    /// The server was heard from again, after this many missed intervals.
+    Recovered(u32),
+    /// A heartbeat arrived this far from when it was expected.
+    Jitter(Duration),
+}
+
+// Tracks how promptly the server's heartbeats arrive.
+struct Liveness {
+    interval: Duration,
+    grace: u32,
+    missed: u32,
+    last_heard: Instant,
+    activity: Activity,
+    events: Sender<LivenessEvent>,
+}
+
+/// What was agreed with the server when connecting, from its `CONNECTED`
+/// frame.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionInfo {
+    version: Version,
+    server: Option<String>,
+    session: Option<String>,
+    send_heartbeat: Option<Duration>,
+    receive_heartbeat: Option<Duration>,
+    headers: Headers,
 }
 
 #[derive(Debug, Default)]
 struct ConnectionState {
-    subscriptions: BTreeMap<Vec<u8>, Sender<Frame>>,
-    receipts: BTreeMap<Vec<u8>, oneshot::Sender<()>>,
+    subscriptions: BTreeMap<Vec<u8>, SubscriptionState>,
+    receipts: BTreeMap<Vec<u8>, PendingReceipt>,
+}
+
+#[derive(Debug)]
+struct SubscriptionState {
+    messages: Inbox,
+    ack_mode: AckMode,
+    // The ack ids of messages yet to be acknowledged, oldest first.
+    outstanding: VecDeque<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct PendingReceipt {
+    done: oneshot::Sender<Result<()>>,
+    // The subscription created by the frame that requested this receipt.
+    subscription: Option<Vec<u8>>,
+}
+
+impl ConnectionState {
+    // Forgets the messages that acknowledging `id` settles, and returns the
+    // ack ids of any earlier ones that need acknowledging individually.
+    // Acknowledgements for messages we do not know of are left to the
+    // server to judge.
+    fn settle(
+        &mut self,
+        subscription: Option<&Vec<u8>>,
+        id: &[u8],
+        up_to: bool,
+    ) -> Result<Vec<Vec<u8>>> {
+        let sub = match subscription {
+            Some(subscription) => self.subscriptions.get_mut(subscription),
+            None => self
+                .subscriptions
+                .values_mut()
+                .find(|sub| sub.outstanding.iter().any(|o| o == id)),
+        };
+        let sub = match sub {
+            Some(sub) => sub,
+            None => return Ok(Vec::new()),
+        };
+        if sub.ack_mode == AckMode::Auto {
+            return Err(StompError::AutoAcknowledged);
+        }
+        let pos = match sub.outstanding.iter().position(|o| o == id) {
+            Some(pos) => pos,
+            None => return Ok(Vec::new()),
+        };
+        match sub.ack_mode {
+            AckMode::ClientIndividual if !up_to => {
+                sub.outstanding.remove(pos);
+                Ok(Vec::new())
+            }
+            AckMode::ClientIndividual => {
+                let mut settled = sub.outstanding.drain(..=pos).collect::<Vec<_>>();
+                settled.pop();
+                Ok(settled)
+            }
+            // One acknowledgement covers every earlier message.
+            _ => {
+                sub.outstanding.drain(..=pos);
+                Ok(Vec::new())
+            }
+        }
+    }
 }
 
 pub(crate) fn wrap<T: AsyncRead + AsyncWrite>(inner: T) -> Framed<T, StompCodec> {
-    Framed::new(inner, StompCodec)
+    Framed::new(inner, StompCodec::default())
+}
+
+impl Default for StompCodec {
+    fn default() -> Self {
+        StompCodec {
+            version: Version::V1_2,
+        }
+    }
+}
+
+impl<T> SetVersion for Framed<T, StompCodec> {
+    fn set_version(&mut self, version: Version) {
+        self.codec_mut().version = version;
+    }
 }
 
 impl Encoder for StompCodec {
     type Item = FrameOrKeepAlive;
     type Error = StompError;
     fn encode(&mut self, item: FrameOrKeepAlive, buf: &mut BytesMut) -> Result<()> {
-        encode_frame(buf, &item)
+        encode_frame(buf, &item, self.version)
     }
 }
 
@@ -99,79 +309,209 @@ impl Decoder for StompCodec {
     type Item = FrameOrKeepAlive;
     type Error = StompError;
     fn decode(&mut self, input: &mut BytesMut) -> Result<Option<FrameOrKeepAlive>> {
-        Ok(parse_frame(input)?)
+        Ok(parse_frame(input, self.version)?)
     }
 }
 
 impl Connection {
-    pub(crate) fn new<T: AsyncRead + AsyncWrite + Send + 'static>(
-        inner: Framed<T, StompCodec>,
+    pub(crate) fn new<F>(
+        inner: F,
         c2s_rx: Receiver<ClientReq>,
-        c2s_ka: Option<Duration>,
-        s2c_ka: Option<Duration>,
-    ) -> Self {
+        info: SessionInfo,
+        activity: Activity,
+        heartbeat_grace: u32,
+    ) -> Self
+    where
+        F: Stream<Item = Result<FrameOrKeepAlive>>
+            + Sink<FrameOrKeepAlive, Error = StompError>
+            + Send
+            + 'static,
+    {
         let (a, b) = inner.split();
         let (subs_a, subs_b) = BiLock::new(ConnectionState::default());
-        let c2s = Self::run_c2s(a, subs_a, c2s_rx, c2s_ka).boxed();
-        let s2c = Self::run_s2c(b, subs_b, s2c_ka).boxed();
+        let c2s = Self::run_c2s(
+            a,
+            subs_a,
+            c2s_rx,
+            info.version,
+            info.send_heartbeat,
+            activity.clone(),
+        )
+        .boxed();
+        let (events_tx, events_rx) = channel(LIVENESS_BUFFER);
+        let liveness = info.receive_heartbeat.map(|interval| Liveness {
+            interval,
+            grace: cmp::max(heartbeat_grace, 1),
+            missed: 0,
+            last_heard: activity.last_read(),
+            activity,
+            events: events_tx,
+        });
+        let s2c = Self::run_s2c(b, subs_b, info.version, liveness).boxed();
         debug!("Built connection process");
-        Connection { s2c, c2s }
+        Connection {
+            s2c,
+            c2s,
+            info,
+            liveness: Some(events_rx),
+        }
+    }
+
+    /// The protocol version agreed with the server.
+    pub fn version(&self) -> Version {
+        self.info.version
+    }
+
+    pub fn session_info(&self) -> &SessionInfo {
+        &self.info
+    }
+
+    /// Takes the stream of heartbeat events from the server. Events are
+    /// discarded if they are not consumed promptly, and there are none if
+    /// the server does not send heartbeats.
+    pub fn liveness(&mut self) -> Option<Receiver<LivenessEvent>> {
+        self.liveness.take()
     }
 
     async fn run_c2s(
         mut inner: impl Sink<FrameOrKeepAlive, Error = StompError> + Unpin,
         subs: BiLock<ConnectionState>,
         mut c2s_rx: Receiver<ClientReq>,
+        version: Version,
         keepalive: Option<Duration>,
+        activity: Activity,
     ) -> Result<()> {
         trace!(
             "Awaiting client messages; keepalive interval: {:?} s",
             keepalive.map(|ka| ka.as_secs_f64()),
         );
+        let mut disconnected = false;
+        let mut closing = None;
         loop {
+            // Only we write to the transport, so the deadline cannot move
+            // while we wait.
             let it = if let Some(keepalive) = keepalive {
-                timeout(keepalive, c2s_rx.next()).await
+                timeout_at(activity.last_written() + keepalive, c2s_rx.next()).await
             } else {
                 Ok(c2s_rx.next().await)
             };
 
-            match it {
-                Ok(Some(ClientReq::Disconnect(req))) => {
+            let req = match it {
+                Ok(Some(req)) => req,
+                Ok(None) if disconnected => return Ok(()),
+                Ok(None) => {
+                    debug!("All clients and subscriptions dropped; disconnecting");
+                    let (receipt, rx) = receipt_req();
+                    closing = Some(rx);
+                    ClientReq::Disconnect(DisconnectReq { receipt })
+                }
+                Err(e) => {
+                    trace!("Timeout elapsed, sending keepalive: {:?}", e);
+                    inner.send(FrameOrKeepAlive::KeepAlive).await?;
+                    continue;
+                }
+            };
+
+            let mut subscription = None;
+            let (mut frame, receipt) = match req {
+                ClientReq::Disconnect(req) => {
+                    disconnected = true;
+                    (req.to_frame(), Some(req.receipt))
+                }
+                ClientReq::Subscribe(req) => {
                     let frame = req.to_frame();
-                    trace!(
-                        "Sending to server {:?}/{:?}",
-                        frame.command,
-                        frame.stringify_headers()
-                    );
                     {
                         let mut state = subs.lock().await;
-                        state.receipts.insert(req.id, req.done);
+                        let sub = SubscriptionState {
+                            messages: req.messages,
+                            ack_mode: req.ack_mode,
+                            outstanding: VecDeque::new(),
+                        };
+                        state.subscriptions.insert(req.id.clone(), sub);
                     };
-
-                    inner.send(FrameOrKeepAlive::Frame(frame)).await?;
-                    trace!("Send Done");
+                    subscription = Some(req.id);
+                    (frame, req.receipt)
                 }
-                Ok(Some(ClientReq::Subscribe(req))) => {
+                ClientReq::Unsubscribe(req) => {
                     let frame = req.to_frame();
                     {
                         let mut state = subs.lock().await;
-                        state.subscriptions.insert(req.id, req.messages);
+                        state.subscriptions.remove(&req.id);
                     };
-                    inner.send(FrameOrKeepAlive::Frame(frame)).await?;
+                    (frame, req.receipt)
                 }
-                Ok(Some(ClientReq::Publish(req))) => {
-                    let frame = req.to_frame();
-                    inner.send(FrameOrKeepAlive::Frame(frame)).await?;
+                ClientReq::Publish(req) => (req.to_frame(), req.receipt),
+                ClientReq::Ack(req) => {
+                    let settled = {
+                        let mut state = subs.lock().await;
+                        state.settle(req.subscription.as_ref(), &req.message_id, req.up_to)
+                    };
+                    let earlier = match settled {
+                        Ok(earlier) => earlier,
+                        Err(e) => {
+                            let _ = req.settled.send(Err(e));
+                            continue;
+                        }
+                    };
+                    let frame = req.to_frame(version);
+                    let _ = req.settled.send(Ok(()));
+                    for id in earlier {
+                        let ack = ack_frame(
+                            Command::Ack,
+                            &id,
+                            req.subscription.as_ref(),
+                            req.transaction.as_ref(),
+                            version,
+                        );
+                        inner.send(FrameOrKeepAlive::Frame(ack)).await?;
+                    }
+                    (frame, req.receipt)
                 }
-                Ok(Some(ClientReq::Ack(req))) => {
-                    let frame = req.to_frame();
-                    inner.send(FrameOrKeepAlive::Frame(frame)).await?;
+                ClientReq::Nack(req) => {
+                    let settled = {
+                        let mut state = subs.lock().await;
+                        state.settle(req.subscription.as_ref(), &req.message_id, false)
+                    };
+                    if let Err(e) = settled {
+                        let _ = req.settled.send(Err(e));
+                        continue;
+                    }
+                    let frame = req.to_frame(version);
+                    let _ = req.settled.send(Ok(()));
+                    (frame, req.receipt)
                 }
-                Ok(None) => return Ok(()),
-                Err(e) => {
-                    trace!("Timeout elapsed, sending keepalive: {:?}", e);
-                    inner.send(FrameOrKeepAlive::KeepAlive).await?;
+                ClientReq::Begin(req) => (req.to_frame(Command::Begin), req.receipt),
+                ClientReq::Commit(req) => (req.to_frame(Command::Commit), req.receipt),
+                ClientReq::Abort(req) => (req.to_frame(Command::Abort), req.receipt),
+            };
+
+            if let Some(receipt) = receipt {
+                frame
+                    .headers
+                    .insert("receipt".as_bytes().to_vec(), receipt.id.clone());
+                let pending = PendingReceipt {
+                    done: receipt.done,
+                    subscription,
+                };
+                let mut state = subs.lock().await;
+                state.receipts.insert(receipt.id, pending);
+            }
+
+            trace!(
+                "Sending to server {:?}/{:?}",
+                frame.command,
+                frame.stringify_headers()
+            );
+            inner.send(FrameOrKeepAlive::Frame(frame)).await?;
+            trace!("Send Done");
+
+            if let Some(receipt) = closing.take() {
+                // Wait for the server to confirm it has seen everything, but
+                // not forever: we stop sending heartbeats in the meantime.
+                if timeout(DISCONNECT_TIMEOUT, receipt).await.is_err() {
+                    warn!("No receipt for DISCONNECT; closing anyway");
                 }
+                return Ok(());
             }
         }
     }
@@ -179,21 +519,27 @@ impl Connection {
     async fn run_s2c(
         mut inner: impl Stream<Item = Result<FrameOrKeepAlive>> + Unpin,
         subs: BiLock<ConnectionState>,
-        keepalive: Option<Duration>,
+        version: Version,
+        mut liveness: Option<Liveness>,
     ) -> Result<()> {
-        let ka_factor = 2;
-
         trace!(
-            "Awaiting server messages; keepalive interval: {:?} s (factor: {})",
-            keepalive.map(|ka| ka.as_secs_f64()),
-            ka_factor,
+            "Awaiting server messages; keepalive interval: {:?} s (grace: {:?})",
+            liveness.as_ref().map(|l| l.interval.as_secs_f64()),
+            liveness.as_ref().map(|l| l.grace),
         );
         loop {
-            let it = if let Some(keepalive) = keepalive {
-                timeout(keepalive * ka_factor, inner.next())
-                    .map_err(|_| StompError::PeerFailed)
-                    .await?
-                    .transpose()?
+            let it = if let Some(liveness) = liveness.as_mut() {
+                match timeout_at(liveness.deadline(), inner.next()).await {
+                    Ok(it) => {
+                        let it = it.transpose()?;
+                        liveness.heard(it == Some(FrameOrKeepAlive::KeepAlive));
+                        it
+                    }
+                    Err(_) => {
+                        liveness.missed()?;
+                        continue;
+                    }
+                }
             } else {
                 inner.next().await.transpose()?
             };
@@ -218,24 +564,59 @@ impl Connection {
                                 "Lookup subscription: {:?}",
                                 String::from_utf8_lossy(&subscription_id)
                             );
-                            let txp = {
-                                let state = subs.lock().await;
-                                state.subscriptions.get(&subscription_id).cloned()
+                            // Record the ack before delivering, so that the
+                            // subscriber cannot acknowledge it first.
+                            let target = {
+                                let mut state = subs.lock().await;
+                                state.subscriptions.get_mut(&subscription_id).map(|sub| {
+                                    let ack = match sub.ack_mode {
+                                        AckMode::Auto => None,
+                                        _ => frame
+                                            .headers
+                                            .get(version.ack_header().as_bytes())
+                                            .cloned(),
+                                    };
+                                    sub.outstanding.extend(ack.clone());
+                                    (sub.messages.clone(), ack)
+                                })
+                            };
+                            let (inbox, ack) = match target {
+                                Some(target) => target,
+                                None => {
+                                    warn!(
+                                        "Received message for unknown subscription: {:?}",
+                                        String::from_utf8_lossy(&subscription_id)
+                                    );
+                                    continue;
+                                }
                             };
 
-                            if let Some(mut tx) = txp {
-                                trace!(
-                                    "Sending to client {:?}/{:?}",
-                                    frame.command,
-                                    frame.stringify_headers()
-                                );
-                                tx.send(frame).await?;
-                                trace!("Send Done");
-                            } else {
-                                warn!(
-                                    "Received message for unknown subscription: {:?}",
-                                    String::from_utf8_lossy(&subscription_id)
-                                );
+                            trace!(
+                                "Sending to client {:?}/{:?}",
+                                frame.command,
+                                frame.stringify_headers()
+                            );
+                            match inbox.deliver(frame).await? {
+                                Delivery::Delivered => trace!("Send Done"),
+                                Delivery::Dropped => {
+                                    debug!(
+                                        "Subscription full, dropped message: {:?}",
+                                        String::from_utf8_lossy(&subscription_id)
+                                    );
+                                    let mut state = subs.lock().await;
+                                    if let Some(sub) = state.subscriptions.get_mut(&subscription_id)
+                                    {
+                                        sub.outstanding.retain(|id| Some(id) != ack.as_ref());
+                                    }
+                                }
+                                Delivery::Closed => {
+                                    debug!(
+                                        "Subscription dropped: {:?}",
+                                        String::from_utf8_lossy(&subscription_id)
+                                    );
+                                    let mut state = subs.lock().await;
+                                    state.subscriptions.remove(&subscription_id);
+                                }
                             }
                         }
                         Command::Receipt => {
@@ -253,11 +634,35 @@ impl Connection {
                                 let mut state = subs.lock().await;
                                 state.receipts.remove(&receipt_id)
                             };
-                            if let Some(tx) = txp {
-                                let _ = tx.send(());
+                            if let Some(pending) = txp {
+                                let _ = pending.done.send(Ok(()));
                                 trace!("Acked receipt: {:?}", String::from_utf8_lossy(&receipt_id))
                             }
                         }
+                        Command::Error => {
+                            warn!(
+                                "Error from server: {:?}: {:?}",
+                                frame.stringify_headers(),
+                                String::from_utf8_lossy(&frame.body)
+                            );
+                            if let Some(receipt_id) = frame.headers.get("receipt-id".as_bytes()) {
+                                let mut state = subs.lock().await;
+                                if let Some(pending) = state.receipts.remove(receipt_id) {
+                                    if let Some(subscription_id) = pending.subscription {
+                                        debug!(
+                                            "Closing failed subscription: {:?}",
+                                            String::from_utf8_lossy(&subscription_id)
+                                        );
+                                        state.subscriptions.remove(&subscription_id);
+                                    }
+                                    let _ = pending
+                                        .done
+                                        .send(Err(StompError::StompError(frame.clone())));
+                                }
+                            }
+                            // The server closes the connection after sending an ERROR frame.
+                            return Err(StompError::StompError(frame));
+                        }
                         _ => warn!("Unhandled frame type from server: {:?}", frame.command),
                     }
                 }
@@ -267,13 +672,81 @@ impl Connection {
     }
 }
 
+impl Liveness {
+    // When we next give up on hearing from the server.
+    fn deadline(&self) -> Instant {
+        self.last_heard + self.interval * (self.missed + 1)
+    }
+
+    fn heard(&mut self, keepalive: bool) {
+        let at = self.activity.last_read();
+        if self.missed > 0 {
+            debug!("Heard from server after {} missed heartbeats", self.missed);
+            self.emit(LivenessEvent::Recovered(self.missed));
+            self.missed = 0;
+        }
+        if keepalive && at > self.last_heard {
+            let jitter = (at - self.last_heard).abs_diff(self.interval);
+            self.emit(LivenessEvent::Jitter(jitter));
+        }
+        self.last_heard = at;
+    }
+
+    // Fails once the server has been silent for `grace` intervals. Bytes of
+    // a frame that has yet to arrive in full still count as hearing from it.
+    fn missed(&mut self) -> Result<()> {
+        if self.activity.last_read() > self.last_heard {
+            self.heard(false);
+        }
+        if Instant::now() < self.deadline() {
+            return Ok(());
+        }
+        self.missed += 1;
+        warn!("Missed {} heartbeat(s) from server", self.missed);
+        self.emit(LivenessEvent::Missed(self.missed));
+        if self.missed >= self.grace {
+            return Err(StompError::PeerFailed);
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, event: LivenessEvent) {
+        if let Err(e) = self.events.try_send(event) {
+            trace!("Discarding liveness event: {:?}", e);
+        }
+    }
+}
+
 pub(crate) async fn connect<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
     conn: T,
     connect: ConnectReq,
 ) -> Result<(Connection, Sender<ClientReq>)> {
-    let mut conn = wrap(conn);
+    let activity = Activity::new();
+    let conn = Tracked::new(conn, activity.clone());
+    connect_framed(wrap(conn), activity, connect).await
+}
 
+/// As `connect`, for transports that carry whole frames rather than bytes,
+/// and so must record their own `activity`.
+pub(crate) async fn connect_framed<F>(
+    mut conn: F,
+    activity: Activity,
+    connect: ConnectReq,
+) -> Result<(Connection, Sender<ClientReq>)>
+where
+    F: Stream<Item = Result<FrameOrKeepAlive>>
+        + Sink<FrameOrKeepAlive, Error = StompError>
+        + SetVersion
+        + Unpin
+        + Send
+        + 'static,
+{
     let connect_frame = connect.to_frame();
+    let accepted = connect_frame
+        .headers
+        .get("accept-version".as_bytes())
+        .map(|v| Version::parse_list(v))
+        .unwrap_or_default();
     trace!("Sending connect frame");
     conn.send(FrameOrKeepAlive::Frame(connect_frame)).await?;
 
@@ -296,31 +769,124 @@ pub(crate) async fn connect<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
             "Error response from server: {:?}: {:?}",
             frame.command, frame.headers
         );
-        return Err(StompError::StompError(frame).into());
+        return Err(StompError::StompError(frame));
     } else if frame.command != Command::Connected {
         warn!(
             "Bad response from server: {:?}: {:?}",
             frame.command,
             frame.stringify_headers(),
         );
-        return Err(StompError::ProtocolError.into());
+        return Err(StompError::ProtocolError);
     }
 
-    let (sx, sy) = parse_keepalive(frame.headers.get("heart-beat".as_bytes()).map(|s| &**s))?;
+    // Servers that only speak 1.0 do not send a version.
+    let version = match frame.headers.get("version".as_bytes()) {
+        Some(v) => std::str::from_utf8(v)?.trim().parse()?,
+        None => Version::V1_0,
+    };
+    if !accepted.contains(&version) {
+        warn!("Server chose a version we did not offer: {}", version);
+        return Err(StompError::UnsupportedVersion(version.to_string()));
+    }
+    debug!("Negotiated protocol version {}", version);
+    conn.set_version(version);
+
+    let (sx, sy) = if version.supports_heartbeats() {
+        parse_keepalive(frame.headers.get("heart-beat".as_bytes()).map(|s| &**s))?
+    } else {
+        (None, None)
+    };
 
     debug!(
-        "heart-beat: cx, cy:{:?}; server-transmit:{:?}; server-receive:{:?}",
-        connect.keepalive, sx, sy,
+        "heart-beat: cx:{:?}; cy:{:?}; server-transmit:{:?}; server-receive:{:?}",
+        connect.send_heartbeat, connect.receive_heartbeat, sx, sy,
     );
-    let c2s_ka = cmp::max(connect.keepalive, sy);
-    let s2c_ka = cmp::max(connect.keepalive, sx);
+    let c2s_ka = negotiate_heartbeat(connect.send_heartbeat, sy);
+    let s2c_ka = negotiate_heartbeat(sx, connect.receive_heartbeat);
 
-    let (c2s_tx, c2s_rx) = channel(1);
-    let mux = Connection::new(conn, c2s_rx, c2s_ka, s2c_ka);
+    let info = SessionInfo::from_connected(frame.headers, version, c2s_ka, s2c_ka)?;
+    let (c2s_tx, c2s_rx) = channel(connect.request_buffer);
+    let mux = Connection::new(conn, c2s_rx, info, activity, connect.heartbeat_grace);
     Ok((mux, c2s_tx))
 }
 
-fn parse_keepalive(headervalue: Option<&[u8]>) -> Result<(Option<Duration>, Option<Duration>)> {
+impl SessionInfo {
+    // Headers that have their own accessors.
+    const KNOWN_HEADERS: &'static [&'static str] = &["version", "server", "session", "heart-beat"];
+
+    fn from_connected(
+        mut headers: Headers,
+        version: Version,
+        send_heartbeat: Option<Duration>,
+        receive_heartbeat: Option<Duration>,
+    ) -> Result<Self> {
+        let mut take = |name: &str| {
+            headers
+                .remove(name.as_bytes())
+                .map(|v| String::from_utf8(v).map_err(|e| e.utf8_error()))
+                .transpose()
+        };
+        let server = take("server")?;
+        let session = take("session")?;
+        for name in Self::KNOWN_HEADERS {
+            headers.remove(name.as_bytes());
+        }
+        Ok(SessionInfo {
+            version,
+            server,
+            session,
+            send_heartbeat,
+            receive_heartbeat,
+            headers,
+        })
+    }
+
+    /// The protocol version agreed with the server.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// The `server` header sent by the server, eg: `RabbitMQ/3.7.8`.
+    pub fn server(&self) -> Option<&str> {
+        self.server.as_deref()
+    }
+
+    /// The `session` header sent by the server.
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    /// How often we send heartbeats, if at all.
+    pub fn send_heartbeat(&self) -> Option<Duration> {
+        self.send_heartbeat
+    }
+
+    /// How often we expect heartbeats from the server, if at all.
+    pub fn receive_heartbeat(&self) -> Option<Duration> {
+        self.receive_heartbeat
+    }
+
+    /// Any other headers of the `CONNECTED` frame.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+}
+
+// Per the spec, heartbeats flow in one direction only if the sender can send
+// them and the receiver wants them, at the slower of the two rates.
+pub(crate) fn negotiate_heartbeat(
+    sender: Option<Duration>,
+    receiver: Option<Duration>,
+) -> Option<Duration> {
+    match (sender, receiver) {
+        (Some(a), Some(b)) => Some(cmp::max(a, b)),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_keepalive(
+    headervalue: Option<&[u8]>,
+) -> Result<(Option<Duration>, Option<Duration>)> {
     if let Some(sxsy) = headervalue {
         let sxsy = std::str::from_utf8(sxsy)?;
         info!("heartbeat: theirs:{:?}", sxsy);
@@ -358,7 +924,7 @@ impl Future for Connection {
             return Poll::Ready(val);
         }
 
-        return Poll::Pending;
+        Poll::Pending
     }
 }
 
@@ -366,9 +932,7 @@ impl DisconnectReq {
     fn to_frame(&self) -> Frame {
         Frame {
             command: Command::Disconnect,
-            headers: btreemap! {
-                "receipt".as_bytes().to_vec()=> self.id.clone()
-            },
+            headers: Headers::new(),
             body: Vec::new(),
         }
     }
@@ -390,49 +954,155 @@ impl SubscribeReq {
 
         Frame {
             command: Command::Subscribe,
-            headers: headers,
+            headers,
             body: Vec::new(),
         }
     }
 }
 
-impl PublishReq {
+impl UnsubscribeReq {
     fn to_frame(&self) -> Frame {
         Frame {
-            command: Command::Send,
+            command: Command::Unsubscribe,
             headers: btreemap! {
-                "destination".as_bytes().to_vec() => self.destination.as_bytes().to_vec(),
-                "content-length".as_bytes().to_vec() => self.body.len().to_string().into_bytes(),
+                "id".as_bytes().to_vec() => self.id.clone(),
             },
-            body: self.body.clone(),
+            body: Vec::new(),
         }
     }
 }
 
-impl AckReq {
+impl PublishReq {
+    // Headers that the library sets itself on a `SEND` frame.
+    const RESERVED_HEADERS: &'static [&'static str] =
+        &["destination", "content-length", "transaction", "receipt"];
+
     fn to_frame(&self) -> Frame {
+        let mut headers = self.message.headers.clone();
+        for name in Self::RESERVED_HEADERS {
+            headers.remove(name.as_bytes());
+        }
+        headers.insert(
+            "destination".as_bytes().to_vec(),
+            self.message.destination.as_bytes().to_vec(),
+        );
+        headers.insert(
+            "content-length".as_bytes().to_vec(),
+            self.message.body.len().to_string().into_bytes(),
+        );
+        if let Some(transaction) = self.transaction.as_ref() {
+            headers.insert("transaction".as_bytes().to_vec(), transaction.clone());
+        }
         Frame {
-            command: Command::Ack,
+            command: Command::Send,
+            headers,
+            body: self.message.body.clone(),
+        }
+    }
+}
+
+impl TransactionReq {
+    fn to_frame(&self, command: Command) -> Frame {
+        Frame {
+            command,
             headers: btreemap! {
-                "id".as_bytes().to_vec() => self.message_id.clone(),
+                "transaction".as_bytes().to_vec() => self.id.clone(),
             },
             body: Vec::new(),
         }
     }
 }
 
+impl AckReq {
+    fn to_frame(&self, version: Version) -> Frame {
+        ack_frame(
+            Command::Ack,
+            &self.message_id,
+            self.subscription.as_ref(),
+            self.transaction.as_ref(),
+            version,
+        )
+    }
+}
+
+impl NackReq {
+    fn to_frame(&self, version: Version) -> Frame {
+        ack_frame(
+            Command::Nack,
+            &self.message_id,
+            self.subscription.as_ref(),
+            self.transaction.as_ref(),
+            version,
+        )
+    }
+}
+
+// STOMP 1.2 identifies the message by its `ack` header, in an `id` header;
+// earlier versions use `message-id`, and 1.1 also needs the subscription.
+fn ack_frame(
+    command: Command,
+    message_id: &[u8],
+    subscription: Option<&Vec<u8>>,
+    transaction: Option<&Vec<u8>>,
+    version: Version,
+) -> Frame {
+    let mut headers = Headers::new();
+    if version >= Version::V1_2 {
+        headers.insert("id".as_bytes().to_vec(), message_id.to_vec());
+    } else {
+        headers.insert("message-id".as_bytes().to_vec(), message_id.to_vec());
+        if let Some(subscription) = subscription {
+            headers.insert("subscription".as_bytes().to_vec(), subscription.clone());
+        }
+    }
+    if let Some(transaction) = transaction {
+        headers.insert("transaction".as_bytes().to_vec(), transaction.clone());
+    }
+    Frame {
+        command,
+        headers,
+        body: Vec::new(),
+    }
+}
+
 impl ConnectReq {
+    // `ConnectOptions` builds its own; this serves the other transports.
+    #[cfg(any(test, feature = "tls", feature = "websocket"))]
+    pub(crate) fn new(
+        credentials: Option<(&str, &str)>,
+        keepalive: Option<Duration>,
+        headers: Headers,
+    ) -> Self {
+        ConnectReq {
+            credentials: credentials.map(|(u, p)| (u.to_string(), p.to_string())),
+            send_heartbeat: keepalive,
+            receive_heartbeat: keepalive,
+            virtual_host: None,
+            versions: Version::ALL.to_vec(),
+            headers,
+            request_buffer: 1,
+            heartbeat_grace: DEFAULT_HEARTBEAT_GRACE,
+        }
+    }
+
     fn to_frame(&self) -> Frame {
         let mut conn_headers = self.headers.clone();
-        conn_headers.insert(
-            "accept-version".as_bytes().to_vec(),
-            "1.2".as_bytes().to_vec(),
-        );
-        if let Some(ref duration) = self.keepalive.as_ref() {
-            let millis = duration.as_millis();
+        // Callers may offer other versions with their own `accept-version`.
+        conn_headers
+            .entry("accept-version".as_bytes().to_vec())
+            .or_insert_with(|| Version::format_list(&self.versions).into_bytes());
+        // As may the virtual host, with their own `host`.
+        if let Some(vhost) = self.virtual_host.as_ref() {
+            conn_headers
+                .entry("host".as_bytes().to_vec())
+                .or_insert_with(|| vhost.as_bytes().to_vec());
+        }
+        if self.send_heartbeat.is_some() || self.receive_heartbeat.is_some() {
+            let cx = self.send_heartbeat.unwrap_or_default().as_millis();
+            let cy = self.receive_heartbeat.unwrap_or_default().as_millis();
             conn_headers.insert(
                 "heart-beat".as_bytes().to_vec(),
-                format!("{},{}", millis, millis).as_bytes().to_vec(),
+                format!("{},{}", cx, cy).into_bytes(),
             );
         }
         if let Some((user, pass)) = self.credentials.as_ref() {
@@ -450,9 +1120,18 @@ impl ConnectReq {
 #[cfg(test)]
 mod test {
     use super::*;
-    use env_logger;
+
+    use std::io;
     use std::time::Duration;
 
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+    use futures::ready;
+    use tokio::time::delay_for;
+
+    use crate::client::Client;
+    use crate::inbox::inbox;
+    use crate::options::Overflow;
+
     #[test]
     fn keepalives_parse_zero_as_none_0() {
         env_logger::try_init().unwrap_or_default();
@@ -480,15 +1159,67 @@ mod test {
         );
     }
 
+    fn negotiated(cx: u64, cy: u64, sxsy: &[u8]) -> (Option<Duration>, Option<Duration>) {
+        let (sx, sy) = parse_keepalive(Some(sxsy)).expect("parse_keepalive");
+        let ms = |n| Some(Duration::from_millis(n)).filter(|d| *d > Duration::from_millis(0));
+        (
+            negotiate_heartbeat(ms(cx), sy),
+            negotiate_heartbeat(sx, ms(cy)),
+        )
+    }
+
+    #[test]
+    fn heartbeats_negotiate_disabled_on_both_sides() {
+        assert_eq!(negotiated(0, 0, b"0,0"), (None, None));
+    }
+
+    #[test]
+    fn heartbeats_negotiate_disabled_by_client() {
+        assert_eq!(negotiated(0, 0, b"10,10"), (None, None));
+    }
+
+    #[test]
+    fn heartbeats_negotiate_disabled_by_server() {
+        assert_eq!(negotiated(10, 10, b"0,0"), (None, None));
+    }
+
+    #[test]
+    fn heartbeats_negotiate_client_send_only() {
+        let ms = Duration::from_millis;
+        assert_eq!(negotiated(10, 0, b"0,20"), (Some(ms(20)), None));
+        assert_eq!(negotiated(10, 0, b"20,0"), (None, None));
+    }
+
+    #[test]
+    fn heartbeats_negotiate_client_receive_only() {
+        let ms = Duration::from_millis;
+        assert_eq!(negotiated(0, 10, b"20,0"), (None, Some(ms(20))));
+        assert_eq!(negotiated(0, 10, b"0,20"), (None, None));
+    }
+
+    #[test]
+    fn heartbeats_negotiate_the_slower_rate() {
+        let ms = Duration::from_millis;
+        assert_eq!(negotiated(10, 30, b"20,5"), (Some(ms(10)), Some(ms(30))));
+        assert_eq!(negotiated(5, 5, b"20,30"), (Some(ms(30)), Some(ms(20))));
+    }
+
+    #[test]
+    fn heartbeats_negotiate_each_direction_independently() {
+        let ms = Duration::from_millis;
+        assert_eq!(negotiated(10, 10, b"0,10"), (Some(ms(10)), None));
+        assert_eq!(negotiated(10, 10, b"10,0"), (None, Some(ms(10))));
+    }
+
     #[test]
     fn connect_req_includes_headers() {
-        let req = ConnectReq {
-            credentials: None,
-            keepalive: None,
-            headers: btreemap! {
+        let req = ConnectReq::new(
+            None,
+            None,
+            btreemap! {
                 "x-canary".as_bytes().to_vec() => "Hi!".as_bytes().to_vec(),
             },
-        };
+        );
         let fr = req.to_frame();
 
         assert_eq!(
@@ -500,15 +1231,16 @@ mod test {
     }
     #[test]
     fn subscribe_req_includes_headers() {
-        let (messages, _) = channel(0);
+        let (messages, _) = inbox(0, Overflow::Block);
         let req = SubscribeReq {
             ack_mode: AckMode::Auto,
             destination: Default::default(),
             id: Default::default(),
-            messages: messages,
+            messages,
             headers: btreemap! {
                 "x-canary".as_bytes().to_vec() => "Hi!".as_bytes().to_vec(),
             },
+            receipt: None,
         };
         let fr = req.to_frame();
 
@@ -520,6 +1252,484 @@ mod test {
         )
     }
 
+    #[test]
+    fn nack_req_uses_ack_id() {
+        let req = NackReq {
+            message_id: "m-1".as_bytes().to_vec(),
+            subscription: None,
+            transaction: None,
+            settled: oneshot::channel().0,
+            receipt: None,
+        };
+        let fr = req.to_frame(Version::V1_2);
+
+        assert_eq!(fr.command, Command::Nack);
+        assert_eq!(
+            fr.headers.get("id".as_bytes()),
+            Some(&"m-1".as_bytes().to_vec())
+        );
+        assert_eq!(fr.headers.get("transaction".as_bytes()), None);
+    }
+
+    #[test]
+    fn nack_req_includes_transaction() {
+        let req = NackReq {
+            message_id: "m-1".as_bytes().to_vec(),
+            subscription: None,
+            transaction: Some("tx-1".as_bytes().to_vec()),
+            settled: oneshot::channel().0,
+            receipt: None,
+        };
+        let fr = req.to_frame(Version::V1_2);
+
+        assert_eq!(
+            fr.headers.get("transaction".as_bytes()),
+            Some(&"tx-1".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn ack_req_uses_message_id_before_1_2() {
+        let req = AckReq {
+            message_id: "m-1".as_bytes().to_vec(),
+            subscription: Some("sub-1".as_bytes().to_vec()),
+            transaction: None,
+            up_to: false,
+            settled: oneshot::channel().0,
+            receipt: None,
+        };
+        let fr = req.to_frame(Version::V1_1);
+
+        assert_eq!(fr.command, Command::Ack);
+        assert_eq!(fr.headers.get("id".as_bytes()), None);
+        assert_eq!(
+            fr.headers.get("message-id".as_bytes()),
+            Some(&"m-1".as_bytes().to_vec())
+        );
+        assert_eq!(
+            fr.headers.get("subscription".as_bytes()),
+            Some(&"sub-1".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn connect_req_sends_separate_heartbeats() {
+        let mut req = ConnectReq::new(None, None, Headers::new());
+        req.send_heartbeat = Some(Duration::from_millis(100));
+        let fr = req.to_frame();
+
+        assert_eq!(
+            fr.headers.get("heart-beat".as_bytes()),
+            Some(&"100,0".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn connect_req_offers_versions() {
+        let mut req = ConnectReq::new(None, None, Headers::new());
+        assert_eq!(
+            req.to_frame().headers.get("accept-version".as_bytes()),
+            Some(&"1.0,1.1,1.2".as_bytes().to_vec())
+        );
+
+        req.versions = vec![Version::V1_2];
+        assert_eq!(
+            req.to_frame().headers.get("accept-version".as_bytes()),
+            Some(&"1.2".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn connect_req_sends_virtual_host() {
+        let mut req = ConnectReq::new(None, None, Headers::new());
+        req.virtual_host = Some("broker.example".to_string());
+        let fr = req.to_frame();
+
+        assert_eq!(
+            fr.headers.get("host".as_bytes()),
+            Some(&"broker.example".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn connect_req_keeps_callers_host() {
+        let mut req = ConnectReq::new(
+            None,
+            None,
+            btreemap! {
+                "host".as_bytes().to_vec() => "/vhost".as_bytes().to_vec(),
+            },
+        );
+        req.virtual_host = Some("broker.example".to_string());
+        let fr = req.to_frame();
+
+        assert_eq!(
+            fr.headers.get("host".as_bytes()),
+            Some(&"/vhost".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn connect_req_keeps_callers_accept_version() {
+        let req = ConnectReq::new(
+            None,
+            None,
+            btreemap! {
+                "accept-version".as_bytes().to_vec() => "1.0,1.1".as_bytes().to_vec(),
+            },
+        );
+        let fr = req.to_frame();
+
+        assert_eq!(
+            fr.headers.get("accept-version".as_bytes()),
+            Some(&"1.0,1.1".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn unsubscribe_req_sets_id() {
+        let req = UnsubscribeReq {
+            id: "sub-1".as_bytes().to_vec(),
+            receipt: None,
+        };
+        let fr = req.to_frame();
+
+        assert_eq!(fr.command, Command::Unsubscribe);
+        assert_eq!(
+            fr.headers.get("id".as_bytes()),
+            Some(&"sub-1".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn publish_req_includes_transaction() {
+        let req = PublishReq {
+            message: Message::new("/queue/a", b"x"),
+            transaction: Some("tx-1".as_bytes().to_vec()),
+            receipt: None,
+        };
+        let fr = req.to_frame();
+
+        assert_eq!(
+            fr.headers.get("transaction".as_bytes()),
+            Some(&"tx-1".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn publish_req_includes_message_headers() {
+        let req = PublishReq {
+            message: Message::new("/queue/a", b"x").header("x-canary", "Hi!"),
+            transaction: None,
+            receipt: None,
+        };
+        let fr = req.to_frame();
+
+        assert_eq!(
+            fr.headers
+                .get("x-canary".as_bytes())
+                .map(|v| String::from_utf8_lossy(v).into_owned()),
+            Some("Hi!".to_string()),
+        )
+    }
+
+    #[test]
+    fn publish_req_overrides_reserved_headers() {
+        let req = PublishReq {
+            message: Message::new("/queue/a", b"xyz")
+                .header("destination", "/queue/elsewhere")
+                .header("content-length", "42")
+                .header("transaction", "tx-bogus")
+                .header("receipt", "r-bogus"),
+            transaction: None,
+            receipt: None,
+        };
+        let fr = req.to_frame();
+
+        assert_eq!(
+            fr.headers.get("destination".as_bytes()),
+            Some(&"/queue/a".as_bytes().to_vec())
+        );
+        assert_eq!(
+            fr.headers.get("content-length".as_bytes()),
+            Some(&"3".as_bytes().to_vec())
+        );
+        assert_eq!(fr.headers.get("transaction".as_bytes()), None);
+        assert_eq!(fr.headers.get("receipt".as_bytes()), None);
+    }
+
+    #[test]
+    fn transaction_req_sets_transaction_header() {
+        let req = TransactionReq {
+            id: "tx-1".as_bytes().to_vec(),
+            receipt: None,
+        };
+        let fr = req.to_frame(Command::Commit);
+
+        assert_eq!(fr.command, Command::Commit);
+        assert_eq!(
+            fr.headers.get("transaction".as_bytes()),
+            Some(&"tx-1".as_bytes().to_vec())
+        );
+    }
+
+    fn with_outstanding(ack_mode: AckMode, ids: &[&str]) -> ConnectionState {
+        let mut state = ConnectionState::default();
+        let sub = SubscriptionState {
+            messages: inbox(0, Overflow::Block).0,
+            ack_mode,
+            outstanding: ids.iter().map(|id| id.as_bytes().to_vec()).collect(),
+        };
+        state.subscriptions.insert("sub-1".as_bytes().to_vec(), sub);
+        state
+    }
+
+    fn outstanding(state: &ConnectionState) -> Vec<String> {
+        state.subscriptions["sub-1".as_bytes()]
+            .outstanding
+            .iter()
+            .map(|id| String::from_utf8_lossy(id).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn client_acks_settle_earlier_messages() {
+        let mut state = with_outstanding(AckMode::Client, &["m-1", "m-2", "m-3"]);
+        let sub = "sub-1".as_bytes().to_vec();
+
+        let earlier = state.settle(Some(&sub), b"m-2", false).expect("settle");
+
+        assert_eq!(earlier, Vec::<Vec<u8>>::new());
+        assert_eq!(outstanding(&state), vec!["m-3"]);
+    }
+
+    #[test]
+    fn individual_acks_settle_one_message() {
+        let mut state = with_outstanding(AckMode::ClientIndividual, &["m-1", "m-2", "m-3"]);
+        let sub = "sub-1".as_bytes().to_vec();
+
+        let earlier = state.settle(Some(&sub), b"m-2", false).expect("settle");
+
+        assert_eq!(earlier, Vec::<Vec<u8>>::new());
+        assert_eq!(outstanding(&state), vec!["m-1", "m-3"]);
+    }
+
+    #[test]
+    fn individual_acks_up_to_a_message_ack_each_earlier_one() {
+        let mut state = with_outstanding(AckMode::ClientIndividual, &["m-1", "m-2", "m-3"]);
+
+        let earlier = state.settle(None, b"m-2", true).expect("settle");
+
+        assert_eq!(earlier, vec!["m-1".as_bytes().to_vec()]);
+        assert_eq!(outstanding(&state), vec!["m-3"]);
+    }
+
+    #[test]
+    fn auto_acked_messages_cannot_be_acked() {
+        let mut state = with_outstanding(AckMode::Auto, &[]);
+        let sub = "sub-1".as_bytes().to_vec();
+
+        let res = state.settle(Some(&sub), b"m-1", false);
+
+        assert!(
+            matches!(res, Err(StompError::AutoAcknowledged)),
+            "Expected an error; got: {:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn unknown_acks_are_left_to_the_server() {
+        let mut state = with_outstanding(AckMode::Client, &["m-1"]);
+
+        let earlier = state.settle(None, b"m-9", false).expect("settle");
+
+        assert_eq!(earlier, Vec::<Vec<u8>>::new());
+        assert_eq!(outstanding(&state), vec!["m-1"]);
+    }
+
+    // An in-memory transport, so that tests decide exactly when bytes arrive
+    // on it, and can see when they were written.
+    struct Wire {
+        incoming: UnboundedReceiver<Vec<u8>>,
+        pending: Vec<u8>,
+        written: UnboundedSender<(Instant, Vec<u8>)>,
+    }
+
+    struct Peer {
+        to_client: UnboundedSender<Vec<u8>>,
+        from_client: UnboundedReceiver<(Instant, Vec<u8>)>,
+    }
+
+    impl AsyncRead for Wire {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.pending.is_empty() {
+                match ready!(Pin::new(&mut self.incoming).poll_next(cx)) {
+                    Some(bytes) => self.pending = bytes,
+                    None => return Poll::Ready(Ok(0)),
+                }
+            }
+            let n = cmp::min(buf.len(), self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for Wire {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let _ = self.written.unbounded_send((Instant::now(), buf.to_vec()));
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Peer {
+        fn send(&self, bytes: &[u8]) {
+            self.to_client
+                .unbounded_send(bytes.to_vec())
+                .expect("send to client");
+        }
+
+        // Everything written since connecting, as offsets from `start`.
+        fn writes_since(&mut self, start: Instant) -> Vec<(Duration, Vec<u8>)> {
+            let mut writes = Vec::new();
+            while let Ok((at, bytes)) = self.from_client.try_recv() {
+                if bytes.starts_with(b"CONNECT") {
+                    continue;
+                }
+                writes.push((at - start, bytes));
+            }
+            writes
+        }
+    }
+
+    // Connects with both heartbeats at 100ms to a server that answers with
+    // the given `heart-beat` header.
+    async fn heartbeating(
+        heart_beat: &str,
+        grace: u32,
+    ) -> (Connection, Sender<ClientReq>, Peer, Receiver<LivenessEvent>) {
+        tokio::time::pause();
+        // Timers fire on whole milliseconds, so start on one.
+        delay_for(Duration::from_millis(1)).await;
+        let (to_client, incoming) = unbounded();
+        let (written, from_client) = unbounded();
+        let wire = Wire {
+            incoming,
+            pending: Vec::new(),
+            written,
+        };
+        let peer = Peer {
+            to_client,
+            from_client,
+        };
+        peer.send(format!("CONNECTED\nversion:1.2\nheart-beat:{}\n\n\0", heart_beat).as_bytes());
+
+        let mut req = ConnectReq::new(None, Some(Duration::from_millis(100)), Headers::new());
+        req.heartbeat_grace = grace;
+        let (mut conn, c2s) = connect(wire, req).await.expect("connect");
+        let liveness = conn.liveness().expect("liveness");
+        (conn, c2s, peer, liveness)
+    }
+
+    #[tokio::test]
+    async fn fails_after_grace_intervals_of_silence() {
+        let (conn, _c2s, _peer, liveness) = heartbeating("100,0", 3).await;
+        let start = Instant::now();
+
+        let res = conn.await;
+        assert!(
+            matches!(res, Err(StompError::PeerFailed)),
+            "Expected peer failure; got: {:?}",
+            res
+        );
+        assert_eq!(Instant::now() - start, Duration::from_millis(300));
+        let events = liveness.collect::<Vec<_>>().await;
+        assert_eq!(
+            events,
+            vec![
+                LivenessEvent::Missed(1),
+                LivenessEvent::Missed(2),
+                LivenessEvent::Missed(3),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_recovered_heartbeats_and_jitter() {
+        let (conn, _c2s, peer, mut liveness) = heartbeating("100,0", 3).await;
+        let conn = tokio::spawn(conn);
+
+        // The paused clock runs ahead whenever the test's main future waits
+        // on another task, so react to events from a task of our own.
+        let server = tokio::spawn(async move {
+            assert_eq!(liveness.next().await, Some(LivenessEvent::Missed(1)));
+            delay_for(Duration::from_millis(20)).await;
+            peer.send(b"\n");
+            assert_eq!(liveness.next().await, Some(LivenessEvent::Recovered(1)));
+            assert_eq!(
+                liveness.next().await,
+                Some(LivenessEvent::Jitter(Duration::from_millis(20)))
+            );
+        });
+        server.await.expect("server");
+        conn.await.expect("join").expect("clean close");
+    }
+
+    #[tokio::test]
+    async fn partial_frames_keep_the_connection_alive() {
+        let (conn, _c2s, peer, liveness) = heartbeating("100,0", 2).await;
+        let conn = tokio::spawn(conn);
+
+        let frame = b"MESSAGE\nsubscription:s-1\nmessage-id:m-1\ndestination:/queue/a\n\nslow\0";
+        for chunk in frame.chunks(6) {
+            delay_for(Duration::from_millis(60)).await;
+            peer.send(chunk);
+        }
+        drop(peer);
+
+        conn.await.expect("join").expect("clean close");
+        assert_eq!(liveness.collect::<Vec<_>>().await, vec![]);
+    }
+
+    #[tokio::test]
+    async fn heartbeats_follow_the_last_write() {
+        let (conn, c2s, mut peer, _liveness) = heartbeating("0,100", 2).await;
+        let start = Instant::now();
+        let client = Client::new(c2s, &conn);
+        let _conn = tokio::spawn(conn);
+
+        delay_for(Duration::from_millis(70)).await;
+        client.publish("/queue/a", b"x").await.expect("publish");
+        delay_for(Duration::from_millis(230)).await;
+
+        let writes = peer.writes_since(start);
+        let ms = Duration::from_millis;
+        assert_eq!(
+            writes.iter().map(|(at, _)| *at).collect::<Vec<_>>(),
+            vec![ms(70), ms(170), ms(270)]
+        );
+        assert!(writes[0].1.starts_with(b"SEND"));
+        assert_eq!(writes[1].1, b"\n");
+        assert_eq!(writes[2].1, b"\n");
+    }
+
     impl FrameOrKeepAlive {
         pub(crate) fn unwrap_frame(self) -> Frame {
             match self {
@@ -1,7 +1,9 @@
+#![allow(unexpected_cfgs)]
+
 use thiserror::Error;
 
 use crate::parser::ParseError;
-use crate::protocol::Frame;
+use crate::protocol::{Frame, Version};
 
 pub type Result<T> = std::result::Result<T, StompError>;
 
@@ -11,8 +13,14 @@ pub enum StompError {
     StompError(Frame),
     #[error("Protocol error")]
     ProtocolError,
-    #[error("Tried to ack a frame with no `ack` header")]
+    #[error("Tried to ack or nack a frame with no `ack` header")]
     NoAckHeader,
+    #[error("Messages on subscriptions in auto mode cannot be acknowledged")]
+    AutoAcknowledged,
+    #[error("Subscription {0:?} fell too far behind")]
+    SubscriptionOverflow(String),
+    #[error("Subscriptions in client mode cannot drop messages, as their acks are cumulative")]
+    CumulativeDrop,
     #[error("peer seems to be unresponsive")]
     PeerFailed,
     #[error("system time")]
@@ -31,6 +39,23 @@ pub enum StompError {
     ConnectionDropped(#[from] futures::channel::mpsc::SendError),
     #[error("Connection dropped")]
     ConnectionDropped2(#[from] futures::channel::oneshot::Canceled),
+    #[error("Not connected to the server")]
+    Disconnected,
+    #[error("Unsupported protocol version: {0}")]
+    UnsupportedVersion(String),
+    #[error("{0} is not supported by STOMP {1}")]
+    NotSupported(&'static str, Version),
+    #[error("Missing {0} header")]
+    MissingHeader(&'static str),
+    #[error("Invalid server name: {0:?}")]
+    InvalidServerName(String),
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("Timed out connecting to the server")]
+    ConnectTimeout,
+    #[cfg(feature = "websocket")]
+    #[error("WebSocket error")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
 }
 
 #[cfg(never)]
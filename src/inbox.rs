@@ -0,0 +1,311 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{
+    channel::mpsc::{channel, Receiver, Sender},
+    future,
+    stream::Stream,
+};
+
+use crate::errors::*;
+use crate::options::Overflow;
+use crate::protocol::Frame;
+
+/// Creates the queue that carries one subscription's messages from the
+/// connection to the `Subscription`.
+pub(crate) fn inbox(capacity: usize, overflow: Overflow) -> (Inbox, Messages) {
+    let (tx, rx) = channel(capacity);
+    let queue = Arc::new(Mutex::new(Queue {
+        messages: tx,
+        spilled: VecDeque::new(),
+    }));
+    let inbox = Inbox {
+        sending: Arc::new(Sending {
+            queue: queue.clone(),
+            overflow,
+        }),
+    };
+    let messages = Messages {
+        messages: rx,
+        queue,
+    };
+    (inbox, messages)
+}
+
+// The connection's end of a subscription's queue. Only `Overflow::Block`
+// waits for the subscriber; otherwise, one slow subscription cannot hold up
+// the connection or any other subscription.
+#[derive(Clone, Debug)]
+pub(crate) struct Inbox {
+    sending: Arc<Sending>,
+}
+
+// Closes the channel once the last inbox is gone.
+#[derive(Debug)]
+struct Sending {
+    queue: Arc<Mutex<Queue>>,
+    overflow: Overflow,
+}
+
+// The subscription's end of its queue.
+#[derive(Debug)]
+pub(crate) struct Messages {
+    messages: Receiver<Frame>,
+    queue: Arc<Mutex<Queue>>,
+}
+
+// Locked by both ends, so that neither overtakes the other.
+#[derive(Debug)]
+struct Queue {
+    // Shared between clones of the inbox, as each sender of a channel is
+    // allowed a message of its own beyond the buffer.
+    messages: Sender<Frame>,
+    // Messages that did not fit in the channel, oldest first. Only used
+    // when spilling or queueing.
+    spilled: VecDeque<Frame>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    Delivered,
+    // The buffer was full, and the message was discarded.
+    Dropped,
+    // The subscription has gone away.
+    Closed,
+}
+
+impl Inbox {
+    pub(crate) async fn deliver(&self, frame: Frame) -> Result<Delivery> {
+        if self.sending.overflow == Overflow::Block {
+            return Ok(self.send(frame).await);
+        }
+
+        let mut queue = self.sending.queue.lock().expect("inbox lock");
+        if !queue.spilled.is_empty() {
+            if queue.messages.is_closed() {
+                return Ok(Delivery::Closed);
+            }
+            // Keep messages in order behind those already spilled.
+            return self.spill(&mut queue, frame);
+        }
+        match queue.messages.try_send(frame) {
+            Ok(()) => Ok(Delivery::Delivered),
+            Err(e) if e.is_disconnected() => Ok(Delivery::Closed),
+            Err(e) => match self.sending.overflow {
+                Overflow::Drop => Ok(Delivery::Dropped),
+                _ => self.spill(&mut queue, e.into_inner()),
+            },
+        }
+    }
+
+    // Waits for room in the channel.
+    async fn send(&self, frame: Frame) -> Delivery {
+        let mut frame = Some(frame);
+        future::poll_fn(|cx| {
+            let mut queue = self.sending.queue.lock().expect("inbox lock");
+            let sent = match queue.messages.poll_ready(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(())) => {
+                    let frame = frame.take().expect("frame sent once");
+                    queue.messages.start_send(frame).is_ok()
+                }
+                Poll::Ready(Err(_)) => false,
+            };
+            Poll::Ready(if sent {
+                Delivery::Delivered
+            } else {
+                Delivery::Closed
+            })
+        })
+        .await
+    }
+
+    fn spill(&self, queue: &mut Queue, frame: Frame) -> Result<Delivery> {
+        match self.sending.overflow {
+            Overflow::Spill => {}
+            Overflow::Queue(max) if queue.spilled.len() < max => {}
+            _ => {
+                let id = frame
+                    .headers
+                    .get("subscription".as_bytes())
+                    .map(|id| String::from_utf8_lossy(id).into_owned())
+                    .unwrap_or_default();
+                return Err(StompError::SubscriptionOverflow(id));
+            }
+        }
+        queue.spilled.push_back(frame);
+        Ok(Delivery::Delivered)
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        let queue = self.sending.queue.lock().expect("inbox lock");
+        queue.messages.is_closed()
+    }
+}
+
+impl Drop for Sending {
+    fn drop(&mut self) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.messages.close_channel();
+        }
+    }
+}
+
+impl Stream for Messages {
+    type Item = Frame;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let mut queue = this.queue.lock().expect("inbox lock");
+        // Anything in the channel was sent before whatever has spilled.
+        match Pin::new(&mut this.messages).poll_next(cx) {
+            Poll::Ready(Some(frame)) => Poll::Ready(Some(frame)),
+            Poll::Ready(None) => Poll::Ready(queue.spilled.pop_front()),
+            Poll::Pending => match queue.spilled.pop_front() {
+                Some(frame) => Poll::Ready(Some(frame)),
+                None => Poll::Pending,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream::StreamExt;
+    use maplit::btreemap;
+
+    use super::*;
+    use crate::protocol::Command;
+
+    fn message(n: u8) -> Frame {
+        Frame {
+            command: Command::Message,
+            headers: btreemap! {
+                "subscription".as_bytes().to_vec() => "sub-1".as_bytes().to_vec(),
+            },
+            body: vec![n],
+        }
+    }
+
+    async fn bodies(messages: &mut Messages, n: usize) -> Vec<u8> {
+        let mut bodies = Vec::new();
+        for _ in 0..n {
+            bodies.extend(messages.next().await.expect("message").body);
+        }
+        bodies
+    }
+
+    #[tokio::test]
+    async fn full_buffers_block() {
+        let (inbox, mut messages) = inbox(0, Overflow::Block);
+        let delivery = inbox.deliver(message(0)).await.unwrap();
+        assert_eq!(delivery, Delivery::Delivered);
+
+        let blocked = inbox.deliver(message(1));
+        futures::pin_mut!(blocked);
+        assert!(futures::poll!(blocked.as_mut()).is_pending());
+        assert_eq!(bodies(&mut messages, 1).await, vec![0]);
+        assert_eq!(blocked.await.unwrap(), Delivery::Delivered);
+        assert_eq!(bodies(&mut messages, 1).await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn spilled_messages_follow_those_buffered() {
+        let (inbox, mut messages) = inbox(1, Overflow::Spill);
+        for n in 0..3 {
+            let delivery = inbox.deliver(message(n)).await.unwrap();
+            assert_eq!(delivery, Delivery::Delivered);
+        }
+        assert_eq!(bodies(&mut messages, 1).await, vec![0]);
+        // The channel has room again, but must not overtake the spill.
+        for n in 3..5 {
+            let delivery = inbox.deliver(message(n)).await.unwrap();
+            assert_eq!(delivery, Delivery::Delivered);
+        }
+        assert_eq!(bodies(&mut messages, 4).await, vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn spilled_messages_outlive_the_connection() {
+        let (inbox, mut messages) = inbox(0, Overflow::Spill);
+        for n in 0..3 {
+            inbox.deliver(message(n)).await.unwrap();
+        }
+        drop(inbox);
+        assert_eq!(bodies(&mut messages, 3).await, vec![0, 1, 2]);
+        assert!(messages.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn full_queues_fail() {
+        let (inbox, mut messages) = inbox(0, Overflow::Queue(2));
+        for n in 0..3 {
+            let delivery = inbox.deliver(message(n)).await.unwrap();
+            assert_eq!(delivery, Delivery::Delivered);
+        }
+        match inbox.deliver(message(3)).await {
+            Err(StompError::SubscriptionOverflow(id)) => assert_eq!(id, "sub-1"),
+            other => panic!("expected overflow, got {:?}", other),
+        }
+        // Reading makes room in the channel, but not in the queue behind it.
+        assert_eq!(bodies(&mut messages, 1).await, vec![0]);
+        assert!(inbox.deliver(message(3)).await.is_err());
+        assert_eq!(bodies(&mut messages, 1).await, vec![1]);
+        let delivery = inbox.deliver(message(3)).await.unwrap();
+        assert_eq!(delivery, Delivery::Delivered);
+        assert_eq!(bodies(&mut messages, 2).await, vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn full_buffers_drop_messages() {
+        let (inbox, mut messages) = inbox(0, Overflow::Drop);
+        let delivery = inbox.deliver(message(0)).await.unwrap();
+        assert_eq!(delivery, Delivery::Delivered);
+        let delivery = inbox.deliver(message(1)).await.unwrap();
+        assert_eq!(delivery, Delivery::Dropped);
+        assert_eq!(bodies(&mut messages, 1).await, vec![0]);
+        let delivery = inbox.deliver(message(2)).await.unwrap();
+        assert_eq!(delivery, Delivery::Delivered);
+        assert_eq!(bodies(&mut messages, 1).await, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn full_buffers_fail() {
+        let (inbox, _messages) = inbox(0, Overflow::Fail);
+        inbox.deliver(message(0)).await.unwrap();
+        match inbox.deliver(message(1)).await {
+            Err(StompError::SubscriptionOverflow(id)) => assert_eq!(id, "sub-1"),
+            other => panic!("expected overflow, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_buffer() {
+        let (inbox, mut messages) = inbox(0, Overflow::Drop);
+        let delivery = inbox.clone().deliver(message(0)).await.unwrap();
+        assert_eq!(delivery, Delivery::Delivered);
+        let delivery = inbox.clone().deliver(message(1)).await.unwrap();
+        assert_eq!(delivery, Delivery::Dropped);
+        assert_eq!(bodies(&mut messages, 1).await, vec![0]);
+    }
+
+    #[tokio::test]
+    async fn dropped_subscriptions_close() {
+        let policies = [
+            Overflow::Block,
+            Overflow::Spill,
+            Overflow::Queue(1),
+            Overflow::Drop,
+        ];
+        for overflow in policies.iter() {
+            let (inbox, messages) = inbox(0, *overflow);
+            drop(messages);
+            let delivery = inbox.deliver(message(0)).await.unwrap();
+            assert_eq!(delivery, Delivery::Closed);
+            assert!(inbox.is_closed());
+        }
+    }
+}
@@ -1,10 +1,40 @@
+mod activity;
+mod broker;
 mod client;
 mod connection;
 mod errors;
+mod inbox;
+mod message;
+mod options;
 mod parser;
 mod protocol;
+mod reconnect;
+mod server;
+#[cfg(feature = "tls")]
+mod tls;
 mod unparser;
+#[cfg(feature = "websocket")]
+mod websocket;
 
-pub use client::{connect, Client};
+pub use broker::Broker;
+pub use client::{
+    connect, connect_with_transport, BrokerAddr, Client, ReceivedMessage, Subscription, Transaction,
+};
+pub use connection::{LivenessEvent, SessionInfo};
 pub use errors::StompError;
-pub use protocol::AckMode;
+pub use message::Message;
+pub use options::{ConnectOptions, Overflow, SubscribeOptions};
+pub use protocol::{AckMode, Headers, Version};
+pub use reconnect::{
+    connect_reconnecting, LifecycleEvent, OfflinePolicy, ReconnectPolicy, ReconnectingConnection,
+};
+pub use server::{
+    accept_with_transport, Listener, Request, RequestKind, Responder, ServerConnection,
+    ServerSession,
+};
+#[cfg(feature = "tls")]
+pub use tls::connect_tls;
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+#[cfg(feature = "websocket")]
+pub use websocket::{connect_websocket, connect_with_websocket};
@@ -0,0 +1,103 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::protocol::Headers;
+
+/// An outgoing message, sent with `Client::send`.
+///
+/// The `destination`, `content-length`, `transaction` and `receipt` headers
+/// are managed by the library, and will be overwritten or removed when the
+/// `SEND` frame is built.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Message {
+    pub(crate) destination: String,
+    pub(crate) headers: Headers,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(destination: &str, body: &[u8]) -> Self {
+        Message {
+            destination: destination.to_string(),
+            headers: Headers::new(),
+            body: body.to_vec(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers
+            .insert(name.as_bytes().to_vec(), value.as_bytes().to_vec());
+        self
+    }
+
+    pub fn headers(mut self, headers: Headers) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    pub fn content_type(self, content_type: &str) -> Self {
+        self.header("content-type", content_type)
+    }
+
+    pub fn persistent(self, persistent: bool) -> Self {
+        self.header("persistent", if persistent { "true" } else { "false" })
+    }
+
+    pub fn priority(self, priority: u8) -> Self {
+        self.header("priority", &priority.to_string())
+    }
+
+    /// Sets the `expires` header, in milliseconds since the Unix epoch.
+    pub fn expires(self, at: SystemTime) -> Self {
+        let millis = at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        self.header("expires", &millis.to_string())
+    }
+
+    pub fn reply_to(self, destination: &str) -> Self {
+        self.header("reply-to", destination)
+    }
+
+    pub fn correlation_id(self, id: &str) -> Self {
+        self.header("correlation-id", id)
+    }
+
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn should_set_well_known_headers() {
+        let msg = Message::new("/queue/a", b"x")
+            .content_type("text/plain")
+            .persistent(true)
+            .priority(4)
+            .expires(UNIX_EPOCH + Duration::from_millis(1234))
+            .reply_to("/queue/b")
+            .correlation_id("c-1");
+
+        let get = |k: &str| {
+            msg.headers
+                .get(k.as_bytes())
+                .map(|v| String::from_utf8_lossy(v).into_owned())
+        };
+        assert_eq!(get("content-type"), Some("text/plain".to_string()));
+        assert_eq!(get("persistent"), Some("true".to_string()));
+        assert_eq!(get("priority"), Some("4".to_string()));
+        assert_eq!(get("expires"), Some("1234".to_string()));
+        assert_eq!(get("reply-to"), Some("/queue/b".to_string()));
+        assert_eq!(get("correlation-id"), Some("c-1".to_string()));
+    }
+}
@@ -7,11 +7,12 @@ use nom::{
     character::streaming::*,
     combinator::map,
     multi::{fold_many0, many0, many1},
+    sequence::terminated,
 };
 use nom::{error::ErrorKind, Err, IResult};
 use thiserror::Error;
 
-use crate::protocol::{Command, Frame, FrameOrKeepAlive, Headers};
+use crate::protocol::{Command, Frame, FrameOrKeepAlive, Headers, Version};
 
 #[derive(Debug, Error)]
 pub struct ParseError {
@@ -21,8 +22,13 @@ pub struct ParseError {
 }
 
 // See grammar described at https://stomp.github.io/stomp-specification-1.2.html#Augmented_BNF
-pub(crate) fn parse_frame(input: &mut BytesMut) -> Result<Option<FrameOrKeepAlive>, ParseError> {
-    match run_parse(input) {
+// Header escapes depend on the protocol version: there are none in 1.0, and
+// 1.1 lacks `\r`. Lines may end in either `\n` or `\r\n`.
+pub(crate) fn parse_frame(
+    input: &mut BytesMut,
+    version: Version,
+) -> Result<Option<FrameOrKeepAlive>, ParseError> {
+    match run_parse(input, version) {
         Ok((remainder, frame)) => {
             let consumed = input.len() - remainder.len();
 
@@ -37,35 +43,39 @@ pub(crate) fn parse_frame(input: &mut BytesMut) -> Result<Option<FrameOrKeepAliv
 
             Ok(Some(frame))
         }
-        Err(Err::Incomplete(_)) => return Ok(None),
-        Err(Err::Error(e)) => return Err(e.into()),
-        Err(Err::Failure(e)) => return Err(e.into()),
+        Err(Err::Incomplete(_)) => Ok(None),
+        Err(Err::Error(e)) => Err(e.into()),
+        Err(Err::Failure(e)) => Err(e.into()),
     }
 }
 
-fn run_parse(input: &[u8]) -> IResult<&[u8], FrameOrKeepAlive> {
+fn run_parse(input: &[u8], version: Version) -> IResult<&[u8], FrameOrKeepAlive> {
     let p = alt((
-        map(parse_inner, FrameOrKeepAlive::Frame),
+        map(|i| parse_inner(i, version), FrameOrKeepAlive::Frame),
         map(parse_keepalive, |()| FrameOrKeepAlive::KeepAlive),
     ));
     p(input)
 }
 
 fn parse_keepalive(input: &[u8]) -> IResult<&[u8], ()> {
-    let (input, _) = newline(input)?;
+    let (input, _) = eol(input)?;
     Ok((input, ()))
 }
 
-fn parse_inner(input: &[u8]) -> IResult<&[u8], Frame> {
+fn eol(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    alt((tag("\r\n"), tag("\n")))(input)
+}
+
+fn parse_inner(input: &[u8], version: Version) -> IResult<&[u8], Frame> {
     let (input, command) = parse_command(input)?;
 
-    let (input, headers) = parse_headers(input)?;
+    let (input, headers) = parse_headers(input, version)?;
 
-    let (input, _) = newline(input)?;
+    let (input, _) = eol(input)?;
 
     let content_length = headers
         .get("content-length".as_bytes())
-        .and_then(|v| std::str::from_utf8(&v).ok())
+        .and_then(|v| std::str::from_utf8(v).ok())
         .and_then(|ls| ls.parse().ok());
 
     let (input, body) = parse_body(content_length, input)?;
@@ -81,38 +91,50 @@ fn parse_inner(input: &[u8]) -> IResult<&[u8], Frame> {
 
 fn parse_command(input: &[u8]) -> IResult<&[u8], Command> {
     let (input, cmd) = alt((
-        map(tag("CONNECT\n"), |_| Command::Connect),
-        map(tag("SEND\n"), |_| Command::Send),
-        map(tag("SUBSCRIBE\n"), |_| Command::Subscribe),
-        map(tag("UNSUBSCRIBE\n"), |_| Command::Unsubscribe),
-        map(tag("DISCONNECT\n"), |_| Command::Disconnect),
-        map(tag("ACK\n"), |_| Command::Ack),
-        map(tag("CONNECTED\n"), |_| Command::Connected),
-        map(tag("MESSAGE\n"), |_| Command::Message),
-        map(tag("RECEIPT\n"), |_| Command::Receipt),
-        map(tag("ERROR\n"), |_| Command::Error),
+        map(terminated(tag("CONNECT"), eol), |_| Command::Connect),
+        map(terminated(tag("STOMP"), eol), |_| Command::Stomp),
+        map(terminated(tag("SEND"), eol), |_| Command::Send),
+        map(terminated(tag("SUBSCRIBE"), eol), |_| Command::Subscribe),
+        map(terminated(tag("UNSUBSCRIBE"), eol), |_| {
+            Command::Unsubscribe
+        }),
+        map(terminated(tag("DISCONNECT"), eol), |_| Command::Disconnect),
+        map(terminated(tag("ACK"), eol), |_| Command::Ack),
+        map(terminated(tag("NACK"), eol), |_| Command::Nack),
+        map(terminated(tag("BEGIN"), eol), |_| Command::Begin),
+        map(terminated(tag("COMMIT"), eol), |_| Command::Commit),
+        map(terminated(tag("ABORT"), eol), |_| Command::Abort),
+        map(terminated(tag("CONNECTED"), eol), |_| Command::Connected),
+        map(terminated(tag("MESSAGE"), eol), |_| Command::Message),
+        map(terminated(tag("RECEIPT"), eol), |_| Command::Receipt),
+        map(terminated(tag("ERROR"), eol), |_| Command::Error),
     ))(input)?;
     Ok((input, cmd))
 }
 
-fn parse_headers(input: &[u8]) -> IResult<&[u8], Headers> {
-    let (input, headers) = fold_many0(parse_header, Headers::new(), |mut headers, (k, v)| {
-        headers.insert(k.to_owned(), v.to_owned());
-        headers
-    })(input)?;
+fn parse_headers(input: &[u8], version: Version) -> IResult<&[u8], Headers> {
+    let (input, headers) = fold_many0(
+        |i| parse_header(i, version),
+        Headers::new(),
+        |mut headers, (k, v)| {
+            headers.insert(k.to_owned(), v.to_owned());
+            headers
+        },
+    )(input)?;
 
     Ok((input, headers))
 }
 
-fn parse_header(input: &[u8]) -> IResult<&[u8], (Vec<u8>, Vec<u8>)> {
-    let (input, name) = many1(parse_header_char)(input)?;
+fn parse_header(input: &[u8], version: Version) -> IResult<&[u8], (Vec<u8>, Vec<u8>)> {
+    let header_char = |i| parse_header_char(i, version);
+    let (input, name) = many1(header_char)(input)?;
     let (input, _) = char(':')(input)?;
 
     // ActiveMQ includes literal colons in the value for some headers, such
     // as session id.
-    let (input, value) = many0(alt((parse_header_char, map(tag(b":"), |_| b':'))))(input)?;
+    let (input, value) = many0(alt((header_char, map(tag(b":"), |_| b':'))))(input)?;
 
-    let (input, _) = newline(input)?;
+    let (input, _) = eol(input)?;
 
     Ok((input, (name, value)))
 }
@@ -127,21 +149,27 @@ fn parse_body(content_length: Option<usize>, input: &[u8]) -> IResult<&[u8], &[u
     Ok((input, body))
 }
 
-fn parse_header_char(input: &[u8]) -> IResult<&[u8], u8> {
+fn parse_header_char(input: &[u8], version: Version) -> IResult<&[u8], u8> {
     match input
         .split_first()
         .ok_or(nom::Err::Incomplete(nom::Needed::Size(1)))?
     {
         (b'\n', input) => Err(nom::Err::Error((input, ErrorKind::Char))),
+        // A carriage return only ends the line when followed by a newline.
+        (b'\r', input) => match input.first() {
+            None => Err(nom::Err::Incomplete(nom::Needed::Size(1))),
+            Some(b'\n') => Err(nom::Err::Error((input, ErrorKind::Char))),
+            Some(_) => Ok((input, b'\r')),
+        },
         (b':', input) => Err(nom::Err::Error((input, ErrorKind::Char))),
-        (b'\\', input) => {
+        (b'\\', input) if version > Version::V1_0 => {
             match input
                 .split_first()
                 .ok_or(nom::Err::Incomplete(nom::Needed::Size(1)))?
             {
                 (b'c', input) => Ok((input, b':')),
                 (b'\\', input) => Ok((input, b'\\')),
-                (b'r', input) => Ok((input, b'\r')),
+                (b'r', input) if version >= Version::V1_2 => Ok((input, b'\r')),
                 (b'n', input) => Ok((input, b'\n')),
                 _ => Err(nom::Err::Error((input, ErrorKind::Char))),
             }
@@ -193,7 +221,7 @@ mod tests {
     fn parse_connect_frame_no_headers() {
         let mut data = BytesMut::from(b"CONNECT\n\n\0" as &[u8]);
 
-        let result = parse_frame(&mut data).expect("parse");
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
         let frame = result.expect("some frame").unwrap_frame();
         assert_eq!(b"" as &[u8], &data);
         assert_eq!(frame.command, Command::Connect);
@@ -205,7 +233,7 @@ mod tests {
             b"CONNECT\naccept-version:1.2\nlogin:guest\npasscode:guest\n\n\0" as &[u8],
         );
 
-        let result = parse_frame(&mut data).expect("parse");
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
         let frame = result.expect("some frame").unwrap_frame();
         assert_eq!(b"" as &[u8], &data);
         assert_eq!(frame.command, Command::Connect);
@@ -218,11 +246,11 @@ mod tests {
     #[test]
     fn parse_escapes_in_headers() {
         let mut data = BytesMut::from(b"CONNECT\n" as &[u8]);
-        data.put_slice(b"colon\\c:cr\r\n" as &[u8]);
+        data.put_slice(b"colon\\c:cr\\r\n" as &[u8]);
         data.put_slice(b"slash\\\\:nl\\n\n" as &[u8]);
         data.put_slice(b"\n\0" as &[u8]);
 
-        let result = parse_frame(&mut data).expect("parse");
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
         let frame = result.expect("some frame").unwrap_frame();
         assert_eq!(b"" as &[u8], &data);
         assert_eq!(
@@ -235,33 +263,73 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_frame_with_crlf_line_endings() {
+        let mut data = BytesMut::from(b"SEND\r\nx:y\r\n\r\nbody\0\r\n" as &[u8]);
+
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
+        let frame = result.expect("some frame").unwrap_frame();
+        assert_eq!(b"\r\n" as &[u8], &data);
+        assert_eq!(frame.command, Command::Send);
+        assert_eq!(frame.headers.get("x".as_bytes()), Some(&b"y".to_vec()));
+        assert_eq!(&*frame.body, b"body");
+    }
+
+    #[test]
+    fn parse_lone_carriage_return_in_header() {
+        let mut data = BytesMut::from(b"SEND\nx:a\rb\n\n\0" as &[u8]);
+
+        let result = parse_frame(&mut data, Version::V1_1).expect("parse");
+        let frame = result.expect("some frame").unwrap_frame();
+        assert_eq!(frame.headers.get("x".as_bytes()), Some(&b"a\rb".to_vec()));
+    }
+
+    #[test]
+    fn parse_incomplete_crlf() {
+        let mut data = BytesMut::from(b"SEND\r" as &[u8]);
+
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
+        assert_eq!(result, None);
+        assert_eq!(b"SEND\r" as &[u8], &data);
+    }
+
     #[test]
     fn parse_send_with_body() {
         let mut data = BytesMut::from(b"SEND\n\nwibble\0" as &[u8]);
 
-        let result = parse_frame(&mut data).expect("parse");
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
         let frame = result.expect("some frame").unwrap_frame();
         assert_eq!(b"" as &[u8], &data);
         assert_eq!(frame.command, Command::Send);
-        assert_eq!(&*frame.body, &*b"wibble");
+        assert_eq!(&*frame.body, b"wibble");
     }
 
     #[test]
     fn parse_send_with_body_and_content_length() {
         let mut data = BytesMut::from(b"SEND\ncontent-length:7\n\nfoo\0bar\0" as &[u8]);
 
-        let result = parse_frame(&mut data).expect("parse");
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
         let frame = result.expect("some frame").unwrap_frame();
         assert_eq!(b"" as &[u8], &data);
         assert_eq!(frame.command, Command::Send);
-        assert_eq!(&*frame.body, &*b"foo\0bar");
+        assert_eq!(&*frame.body, b"foo\0bar");
     }
 
     #[test]
     fn parse_keepalive() {
         let mut data = BytesMut::from(b"\nstuff" as &[u8]);
 
-        let result = parse_frame(&mut data).expect("parse");
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
+        let frame = result.expect("some frame");
+        assert_eq!(b"stuff" as &[u8], &data);
+        assert_eq!(FrameOrKeepAlive::KeepAlive, frame);
+    }
+
+    #[test]
+    fn parse_crlf_keepalive() {
+        let mut data = BytesMut::from(b"\r\nstuff" as &[u8]);
+
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
         let frame = result.expect("some frame");
         assert_eq!(b"stuff" as &[u8], &data);
         assert_eq!(FrameOrKeepAlive::KeepAlive, frame);
@@ -282,11 +350,11 @@ mod tests {
             \0\n" as &[u8],
         );
 
-        let result = parse_frame(&mut data).expect("parse");
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
         let frame = result.expect("some frame").unwrap_frame();
         assert_eq!(b"\n" as &[u8], &data);
         assert_eq!(frame.command, Command::Connected);
-        assert_eq!(&*frame.body, &*b"");
+        assert_eq!(&*frame.body, b"");
     }
     #[test]
     fn rabbitmq_example() {
@@ -302,10 +370,10 @@ mod tests {
             \0\n" as &[u8],
         );
 
-        let result = parse_frame(&mut data).expect("parse");
+        let result = parse_frame(&mut data, Version::V1_2).expect("parse");
         let frame = result.expect("some frame").unwrap_frame();
         assert_eq!(b"\n" as &[u8], &data);
         assert_eq!(frame.command, Command::Connected);
-        assert_eq!(&*frame.body, &*b"");
+        assert_eq!(&*frame.body, b"");
     }
 }
@@ -5,18 +5,34 @@ use crate::errors::*;
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum AckMode {
     Auto,
+    /// Acknowledging a message also acknowledges every earlier message on
+    /// the same subscription.
+    Client,
     ClientIndividual,
 }
 
+/// A STOMP protocol version.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Version {
+    V1_0,
+    V1_1,
+    V1_2,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Command {
     // Client Commands
     Connect,
+    Stomp,
     Send,
     Subscribe,
     Unsubscribe,
     Disconnect,
     Ack,
+    Nack,
+    Begin,
+    Commit,
+    Abort,
 
     // Server commands
     Connected,
@@ -31,6 +47,7 @@ pub struct Frame {
     pub headers: Headers,
     pub body: Vec<u8>,
 }
+#[allow(dead_code)]
 #[derive(Clone, Eq, PartialEq, Debug, Hash)]
 pub struct StringyFrame {
     pub command: Command,
@@ -46,9 +63,85 @@ pub enum FrameOrKeepAlive {
 
 impl AckMode {
     pub(crate) fn as_str(&self) -> &'static str {
-        match self {
-            &AckMode::Auto => "auto",
-            &AckMode::ClientIndividual => "client-individual",
+        match *self {
+            AckMode::Auto => "auto",
+            AckMode::Client => "client",
+            AckMode::ClientIndividual => "client-individual",
+        }
+    }
+}
+
+impl Version {
+    pub const ALL: &'static [Version] = &[Version::V1_0, Version::V1_1, Version::V1_2];
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Version::V1_0 => "1.0",
+            Version::V1_1 => "1.1",
+            Version::V1_2 => "1.2",
+        }
+    }
+
+    /// The header of a `MESSAGE` frame that identifies it in an `ACK` or `NACK`.
+    pub(crate) fn ack_header(self) -> &'static str {
+        if self >= Version::V1_2 {
+            "ack"
+        } else {
+            "message-id"
+        }
+    }
+
+    pub(crate) fn supports_nack(self) -> bool {
+        self >= Version::V1_1
+    }
+
+    pub(crate) fn supports_heartbeats(self) -> bool {
+        self >= Version::V1_1
+    }
+
+    /// Parses an `accept-version` header, ignoring versions we do not know.
+    pub(crate) fn parse_list(value: &[u8]) -> Vec<Version> {
+        value
+            .split(|&b| b == b',')
+            .filter_map(|v| std::str::from_utf8(v).ok()?.trim().parse().ok())
+            .collect()
+    }
+
+    pub(crate) fn format_list(versions: &[Version]) -> String {
+        versions
+            .iter()
+            .map(Version::as_str)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Version {
+    type Err = StompError;
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "1.0" => Ok(Version::V1_0),
+            "1.1" => Ok(Version::V1_1),
+            "1.2" => Ok(Version::V1_2),
+            _ => Err(StompError::UnsupportedVersion(input.to_string())),
+        }
+    }
+}
+
+impl std::str::FromStr for AckMode {
+    type Err = StompError;
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "auto" => Ok(AckMode::Auto),
+            "client" => Ok(AckMode::Client),
+            "client-individual" => Ok(AckMode::ClientIndividual),
+            _ => Err(StompError::ProtocolError),
         }
     }
 }
@@ -59,11 +152,16 @@ impl Command {
     pub(crate) fn as_str(&self) -> &'static str {
         match self {
             Command::Connect => "CONNECT",
+            Command::Stomp => "STOMP",
             Command::Send => "SEND",
             Command::Subscribe => "SUBSCRIBE",
             Command::Unsubscribe => "UNSUBSCRIBE",
             Command::Disconnect => "DISCONNECT",
             Command::Ack => "ACK",
+            Command::Nack => "NACK",
+            Command::Begin => "BEGIN",
+            Command::Commit => "COMMIT",
+            Command::Abort => "ABORT",
             Command::Connected => "CONNECTED",
             Command::Message => "MESSAGE",
             Command::Receipt => "RECEIPT",
@@ -77,11 +175,16 @@ impl std::str::FromStr for Command {
     fn from_str(input: &str) -> Result<Self> {
         match input {
             "CONNECT" => Ok(Command::Connect),
+            "STOMP" => Ok(Command::Stomp),
             "SEND" => Ok(Command::Send),
             "SUBSCRIBE" => Ok(Command::Subscribe),
             "UNSUBSCRIBE" => Ok(Command::Unsubscribe),
             "DISCONNECT" => Ok(Command::Disconnect),
             "ACK" => Ok(Command::Ack),
+            "NACK" => Ok(Command::Nack),
+            "BEGIN" => Ok(Command::Begin),
+            "COMMIT" => Ok(Command::Commit),
+            "ABORT" => Ok(Command::Abort),
             "CONNECTED" => Ok(Command::Connected),
             "MESSAGE" => Ok(Command::Message),
             "RECEIPT" => Ok(Command::Receipt),
@@ -0,0 +1,573 @@
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::future::{self, BoxFuture, Either, FutureExt};
+use futures::{pin_mut, stream::StreamExt};
+use log::*;
+use tokio::time::delay_for;
+
+use crate::client::{self, Client, Requests};
+use crate::connection::{ClientReq, Connection, SubscribeReq};
+use crate::errors::*;
+use crate::inbox::Inbox;
+use crate::options::ConnectOptions;
+use crate::protocol::{AckMode, Headers};
+
+const EVENT_BUFFER: usize = 16;
+
+/// How a reconnecting client re-dials the server, and what it does with
+/// requests made while it is disconnected.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Delay before the first reconnection attempt.
+    pub initial_backoff: Duration,
+    /// Upper bound on the delay between attempts.
+    pub max_backoff: Duration,
+    /// Factor applied to the delay after each failed attempt.
+    pub multiplier: u32,
+    /// Give up after this many consecutive failed attempts.
+    pub max_attempts: Option<usize>,
+    pub offline: OfflinePolicy,
+}
+
+/// What to do with publishes made while disconnected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OfflinePolicy {
+    /// Hold up to this many requests, and send them once reconnected.
+    Buffer(usize),
+    /// Fail publishes, acknowledgements and transactions with
+    /// `StompError::Disconnected`.
+    Reject,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LifecycleEvent {
+    Connected,
+    Disconnected(String),
+    /// A subscription (identified by its id) was re-established after a
+    /// reconnection.
+    Resubscribed(String),
+}
+
+#[must_use = "The connection future must be polled to make progress"]
+pub struct ReconnectingConnection {
+    inner: BoxFuture<'static, Result<()>>,
+    events: Option<Receiver<LifecycleEvent>>,
+}
+
+#[derive(Debug)]
+struct SubscriptionEntry {
+    destination: String,
+    ack_mode: AckMode,
+    headers: Headers,
+    messages: Inbox,
+}
+
+struct Supervisor {
+    options: ConnectOptions,
+    subscriptions: BTreeMap<Vec<u8>, SubscriptionEntry>,
+    buffer: VecDeque<ClientReq>,
+    events: Sender<LifecycleEvent>,
+    // Shared with the client, so requests fail fast under
+    // `OfflinePolicy::Reject`.
+    rejecting: Arc<AtomicBool>,
+}
+
+/// Connects to `addr` (given as `host:port`), and transparently reconnects
+/// whenever the connection is lost, re-establishing any active
+/// subscriptions.
+///
+/// The returned `Client` behaves as one from `connect`. The connection
+/// future only resolves once every client handle and subscription has been
+/// dropped, after a `disconnect`, or when the policy gives up reconnecting.
+///
+/// The client's `version`, `server` and `session_info` describe the first
+/// session, and are not updated after reconnecting.
+pub async fn connect_reconnecting(
+    addr: &str,
+    credentials: Option<(&str, &str)>,
+    keepalive: Option<Duration>,
+    headers: Headers,
+    policy: ReconnectPolicy,
+) -> Result<(ReconnectingConnection, Client)> {
+    client::options(credentials, keepalive, headers)
+        .address(addr)
+        .reconnect(policy)
+        .connect_reconnecting()
+        .await
+}
+
+pub(crate) async fn supervise(options: ConnectOptions) -> Result<(ReconnectingConnection, Client)> {
+    let (conn, client) = options.connect().await?;
+
+    let (c2s_tx, c2s_rx) = channel(options.request_buffer_size());
+    let (events_tx, events_rx) = channel(EVENT_BUFFER);
+    let rejecting = Arc::new(AtomicBool::new(false));
+    let supervisor = Supervisor {
+        options,
+        subscriptions: BTreeMap::new(),
+        buffer: VecDeque::new(),
+        events: events_tx,
+        rejecting: rejecting.clone(),
+    };
+
+    let inner = supervisor.run(conn, client.c2s, c2s_rx).boxed();
+    let conn = ReconnectingConnection {
+        inner,
+        events: Some(events_rx),
+    };
+    let client = Client {
+        c2s: Requests::rejecting(c2s_tx, rejecting),
+        ..client
+    };
+    Ok((conn, client))
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2,
+            max_attempts: None,
+            offline: OfflinePolicy::Buffer(1024),
+        }
+    }
+}
+
+impl ReconnectingConnection {
+    /// Takes the stream of lifecycle events. Events are discarded if they
+    /// are not consumed promptly.
+    pub fn events(&mut self) -> Option<Receiver<LifecycleEvent>> {
+        self.events.take()
+    }
+}
+
+impl Future for ReconnectingConnection {
+    type Output = Result<()>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+impl Supervisor {
+    async fn run(
+        mut self,
+        mut conn: Connection,
+        mut inner: Requests,
+        mut c2s_rx: Receiver<ClientReq>,
+    ) -> Result<()> {
+        self.emit(LifecycleEvent::Connected);
+        let mut lost = None;
+        loop {
+            let mut disconnecting = false;
+            let res = match lost.take() {
+                Some(res) => res,
+                None => loop {
+                    match future::select(&mut conn, c2s_rx.next()).await {
+                        Either::Left((res, _)) => break res,
+                        Either::Right((None, _)) => {
+                            debug!("All clients dropped; closing connection");
+                            drop(inner);
+                            return conn.await;
+                        }
+                        Either::Right((Some(req), _)) => {
+                            if let ClientReq::Disconnect(_) = req {
+                                disconnecting = true;
+                            }
+                            if let Some(res) = self.forward(&mut conn, &inner, req).await {
+                                break res;
+                            }
+                        }
+                    }
+                },
+            };
+
+            if disconnecting {
+                return res;
+            }
+            warn!("Connection lost: {:?}", res);
+            let reject = self.options.reconnect_policy().offline == OfflinePolicy::Reject;
+            self.rejecting.store(reject, Ordering::SeqCst);
+            self.emit(LifecycleEvent::Disconnected(format!("{:?}", res)));
+
+            match self.reconnect(&mut c2s_rx).await? {
+                Some((c, i)) => {
+                    conn = c;
+                    inner = i;
+                    self.rejecting.store(false, Ordering::SeqCst);
+                }
+                None => return Ok(()),
+            }
+            self.emit(LifecycleEvent::Connected);
+            lost = self.resubscribe(&mut conn, &inner).await;
+        }
+    }
+
+    // Sends a request to the current connection, returning the connection's
+    // result if it finishes first.
+    async fn forward(
+        &mut self,
+        conn: &mut Connection,
+        inner: &Requests,
+        req: ClientReq,
+    ) -> Option<Result<()>> {
+        match &req {
+            ClientReq::Subscribe(req) => {
+                let entry = SubscriptionEntry {
+                    destination: req.destination.clone(),
+                    ack_mode: req.ack_mode.clone(),
+                    headers: req.headers.clone(),
+                    messages: req.messages.clone(),
+                };
+                self.subscriptions.insert(req.id.clone(), entry);
+            }
+            ClientReq::Unsubscribe(req) => {
+                self.subscriptions.remove(&req.id);
+            }
+            _ => {}
+        }
+
+        let send = inner.send(req);
+        pin_mut!(send);
+        match future::select(conn, send).await {
+            Either::Left((res, _)) => Some(res),
+            Either::Right(_) => None,
+        }
+    }
+
+    async fn reconnect(
+        &mut self,
+        c2s_rx: &mut Receiver<ClientReq>,
+    ) -> Result<Option<(Connection, Requests)>> {
+        let policy = self.options.reconnect_policy().clone();
+        let mut backoff = policy.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            debug!("Reconnecting in {:?}", backoff);
+            let delay = delay_for(backoff);
+            pin_mut!(delay);
+            loop {
+                match future::select(&mut delay, c2s_rx.next()).await {
+                    Either::Left(((), _)) => break,
+                    Either::Right((None, _)) => return Ok(None),
+                    Either::Right((Some(req), _)) => {
+                        if !self.offline(req) {
+                            return Ok(None);
+                        }
+                    }
+                }
+            }
+
+            attempts += 1;
+            match self.options.connect().await {
+                Ok((conn, client)) => {
+                    info!("Reconnected after {} attempt(s)", attempts);
+                    return Ok(Some((conn, client.c2s)));
+                }
+                Err(e) => {
+                    warn!("Reconnection attempt {} failed: {:?}", attempts, e);
+                    if policy.max_attempts.is_some_and(|max| attempts >= max) {
+                        return Err(e);
+                    }
+                    backoff = cmp::min(backoff * policy.multiplier, policy.max_backoff);
+                }
+            }
+        }
+    }
+
+    // Handles a request made while disconnected. Returns false once the
+    // client has asked to disconnect.
+    fn offline(&mut self, req: ClientReq) -> bool {
+        match req {
+            ClientReq::Disconnect(req) => {
+                let _ = req.receipt.done.send(Ok(()));
+                return false;
+            }
+            ClientReq::Subscribe(_) => self.buffer.push_back(req),
+            ClientReq::Unsubscribe(req) => {
+                self.subscriptions.remove(&req.id);
+                // A subscription made while offline was never sent.
+                self.buffer.retain(|buffered| match buffered {
+                    ClientReq::Subscribe(sub) => sub.id != req.id,
+                    _ => true,
+                });
+                if let Some(receipt) = req.receipt {
+                    let _ = receipt.done.send(Ok(()));
+                }
+            }
+            ClientReq::Publish(ref publish) if publish.transaction.is_none() => {
+                match self.options.reconnect_policy().offline {
+                    OfflinePolicy::Buffer(max) if self.buffer.len() < max => {
+                        self.buffer.push_back(req)
+                    }
+                    _ => Self::reject(req),
+                }
+            }
+            // Acknowledgements and transactions belong to the lost session.
+            req => Self::reject(req),
+        }
+        true
+    }
+
+    fn reject(req: ClientReq) {
+        warn!("Not connected; dropping request: {:?}", req);
+        if let Some(receipt) = req.into_receipt() {
+            let _ = receipt.done.send(Err(StompError::Disconnected));
+        }
+    }
+
+    // Replays active subscriptions and buffered requests onto a new
+    // connection, returning the connection's result if it finishes first.
+    async fn resubscribe(&mut self, conn: &mut Connection, inner: &Requests) -> Option<Result<()>> {
+        self.subscriptions
+            .retain(|_, entry| !entry.messages.is_closed());
+        let replay = self
+            .subscriptions
+            .iter()
+            .map(|(id, entry)| SubscribeReq {
+                destination: entry.destination.clone(),
+                id: id.clone(),
+                ack_mode: entry.ack_mode.clone(),
+                messages: entry.messages.clone(),
+                headers: entry.headers.clone(),
+                receipt: None,
+            })
+            .collect::<Vec<_>>();
+
+        for req in replay {
+            let id = String::from_utf8_lossy(&req.id).into_owned();
+            if let Some(res) = self.forward(conn, inner, ClientReq::Subscribe(req)).await {
+                return Some(res);
+            }
+            self.emit(LifecycleEvent::Resubscribed(id));
+        }
+
+        while let Some(req) = self.buffer.pop_front() {
+            if let Some(res) = self.forward(conn, inner, req).await {
+                return Some(res);
+            }
+        }
+        None
+    }
+
+    fn emit(&mut self, event: LifecycleEvent) {
+        trace!("Lifecycle event: {:?}", event);
+        let _ = self.events.try_send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::sink::SinkExt;
+    use maplit::btreemap;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::connection::{wrap, StompCodec};
+    use crate::protocol::{Command, Frame, FrameOrKeepAlive};
+
+    async fn accept(listener: &mut TcpListener) -> Framed<TcpStream, StompCodec> {
+        let (conn, _) = listener.accept().await.expect("accept");
+        let mut server = wrap(conn);
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.command, Command::Connect);
+        let connected = Frame {
+            command: Command::Connected,
+            headers: btreemap! {
+                "version".as_bytes().to_vec() => "1.2".as_bytes().to_vec(),
+            },
+            body: Vec::new(),
+        };
+        server
+            .send(FrameOrKeepAlive::Frame(connected))
+            .await
+            .expect("send connected");
+        server
+    }
+
+    async fn next_frame(server: &mut Framed<TcpStream, StompCodec>) -> Frame {
+        server
+            .next()
+            .await
+            .expect("some frame")
+            .expect("frame")
+            .unwrap_frame()
+    }
+
+    fn policy(offline: OfflinePolicy) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            offline,
+            ..ReconnectPolicy::default()
+        }
+    }
+
+    async fn start(
+        offline: OfflinePolicy,
+    ) -> (
+        TcpListener,
+        Framed<TcpStream, StompCodec>,
+        Client,
+        Receiver<LifecycleEvent>,
+    ) {
+        start_with(policy(offline)).await
+    }
+
+    async fn start_with(
+        policy: ReconnectPolicy,
+    ) -> (
+        TcpListener,
+        Framed<TcpStream, StompCodec>,
+        Client,
+        Receiver<LifecycleEvent>,
+    ) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr").to_string();
+
+        let (server, res) = futures::join!(
+            accept(&mut listener),
+            connect_reconnecting(&addr, None, None, Headers::new(), policy)
+        );
+        let (mut conn, client) = res.expect("connect");
+        let events = conn.events().expect("events");
+        tokio::spawn(conn);
+        (listener, server, client, events)
+    }
+
+    #[tokio::test]
+    async fn should_resubscribe_and_flush_buffer_after_reconnecting() {
+        env_logger::try_init().unwrap_or_default();
+        let (mut listener, mut server, client, mut events) = start(OfflinePolicy::Buffer(16)).await;
+        assert_eq!(events.next().await, Some(LifecycleEvent::Connected));
+
+        let mut sub = client
+            .subscribe("/queue/a", "one", AckMode::Auto, Headers::new())
+            .await
+            .expect("subscribe");
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.command, Command::Subscribe);
+
+        drop(server);
+        match events.next().await {
+            Some(LifecycleEvent::Disconnected(_)) => {}
+            other => panic!("Expected disconnection; got: {:?}", other),
+        }
+
+        client.publish("/queue/a", b"later").await.expect("publish");
+
+        let mut server = accept(&mut listener).await;
+        assert_eq!(events.next().await, Some(LifecycleEvent::Connected));
+
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.command, Command::Subscribe);
+        assert_eq!(frame.headers["id".as_bytes()], b"one");
+        assert_eq!(frame.headers["destination".as_bytes()], b"/queue/a");
+        assert_eq!(
+            events.next().await,
+            Some(LifecycleEvent::Resubscribed("one".to_string()))
+        );
+
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.command, Command::Send);
+        assert_eq!(frame.body, b"later");
+
+        let message = Frame {
+            command: Command::Message,
+            headers: btreemap! {
+                "subscription".as_bytes().to_vec() => "one".as_bytes().to_vec(),
+            },
+            body: b"hello".to_vec(),
+        };
+        server
+            .send(FrameOrKeepAlive::Frame(message))
+            .await
+            .expect("send message");
+        let received = sub.next().await.expect("message");
+        assert_eq!(received.body(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn should_forget_subscriptions_closed_while_disconnected() {
+        env_logger::try_init().unwrap_or_default();
+        // Long enough for the requests below to arrive while offline.
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(200),
+            ..policy(OfflinePolicy::Buffer(16))
+        };
+        let (mut listener, server, client, mut events) = start_with(policy).await;
+        assert_eq!(events.next().await, Some(LifecycleEvent::Connected));
+
+        drop(server);
+        match events.next().await {
+            Some(LifecycleEvent::Disconnected(_)) => {}
+            other => panic!("Expected disconnection; got: {:?}", other),
+        }
+
+        let sub = client
+            .subscribe("/queue/a", "one", AckMode::Auto, Headers::new())
+            .await
+            .expect("subscribe");
+        sub.unsubscribe().await.expect("unsubscribe");
+        client.publish("/queue/a", b"later").await.expect("publish");
+
+        let mut server = accept(&mut listener).await;
+        assert_eq!(events.next().await, Some(LifecycleEvent::Connected));
+
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.command, Command::Send);
+        assert_eq!(frame.body, b"later");
+    }
+
+    #[tokio::test]
+    async fn should_reject_publishes_while_disconnected() {
+        env_logger::try_init().unwrap_or_default();
+        let (_listener, server, client, mut events) = start(OfflinePolicy::Reject).await;
+        assert_eq!(events.next().await, Some(LifecycleEvent::Connected));
+
+        drop(server);
+        match events.next().await {
+            Some(LifecycleEvent::Disconnected(_)) => {}
+            other => panic!("Expected disconnection; got: {:?}", other),
+        }
+
+        match client.publish_with_receipt("/queue/a", b"x").await {
+            Err(StompError::Disconnected) => {}
+            other => panic!("Expected rejection; got: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_fail_plain_publishes_while_rejecting() {
+        env_logger::try_init().unwrap_or_default();
+        let (mut listener, server, client, mut events) = start(OfflinePolicy::Reject).await;
+        assert_eq!(events.next().await, Some(LifecycleEvent::Connected));
+
+        drop(server);
+        match events.next().await {
+            Some(LifecycleEvent::Disconnected(_)) => {}
+            other => panic!("Expected disconnection; got: {:?}", other),
+        }
+
+        match client.publish("/queue/a", b"x").await {
+            Err(StompError::Disconnected) => {}
+            other => panic!("Expected rejection; got: {:?}", other),
+        }
+
+        let mut server = accept(&mut listener).await;
+        assert_eq!(events.next().await, Some(LifecycleEvent::Connected));
+        client.publish("/queue/a", b"y").await.expect("publish");
+        let frame = next_frame(&mut server).await;
+        assert_eq!(frame.command, Command::Send);
+        assert_eq!(frame.body, b"y");
+    }
+}
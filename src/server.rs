@@ -0,0 +1,888 @@
+use std::{
+    cmp,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{
+    channel::{
+        mpsc::{channel, unbounded, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    future::{self, BoxFuture, FutureExt},
+    select,
+    sink::{Sink, SinkExt},
+    stream::{Stream, StreamExt},
+};
+use log::*;
+use maplit::btreemap;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::time::{delay_until, timeout_at, Instant};
+
+use crate::activity::{Activity, Tracked};
+use crate::connection::{
+    negotiate_heartbeat, parse_keepalive, wrap, SetVersion, DEFAULT_HEARTBEAT_GRACE,
+};
+use crate::errors::*;
+use crate::message::Message;
+use crate::protocol::{AckMode, Command, Frame, FrameOrKeepAlive, Headers, Version};
+
+const SERVER_NAME: &str = concat!("stomping/", env!("CARGO_PKG_VERSION"));
+
+static NEXT_SESSION: AtomicUsize = AtomicUsize::new(0);
+
+/// Accepts STOMP connections over TCP.
+#[derive(Debug)]
+pub struct Listener {
+    inner: TcpListener,
+    heartbeat: Option<Duration>,
+    heartbeat_grace: u32,
+}
+
+/// Drives a single accepted connection. Completes once every handle to the
+/// session has been dropped and queued frames are written, after sending an
+/// `ERROR` frame, or when the connection fails.
+#[must_use = "The connection future must be polled to make progress"]
+pub struct ServerConnection {
+    c2s: Option<BoxFuture<'static, Result<()>>>,
+    s2c: BoxFuture<'static, Result<()>>,
+}
+
+/// The requests made by a connected client, and the means to answer them.
+///
+/// The stream ends when the client disconnects or the connection fails.
+pub struct ServerSession {
+    requests: Receiver<Request>,
+    responder: Responder,
+    session: String,
+    version: Version,
+    connect_headers: Headers,
+}
+
+/// Sends frames to a connected client.
+#[derive(Clone, Debug)]
+pub struct Responder {
+    s2c: UnboundedSender<Frame>,
+    version: Version,
+}
+
+/// A frame sent by a connected client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Request {
+    pub kind: RequestKind,
+    /// The `receipt` header, if the client asked for one.
+    pub receipt: Option<String>,
+    /// All of the frame's headers, as sent.
+    pub headers: Headers,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RequestKind {
+    Send {
+        destination: String,
+        transaction: Option<String>,
+        body: Vec<u8>,
+    },
+    Subscribe {
+        destination: String,
+        id: String,
+        ack: AckMode,
+    },
+    Unsubscribe {
+        id: String,
+    },
+    Ack {
+        id: String,
+        transaction: Option<String>,
+    },
+    Nack {
+        id: String,
+        transaction: Option<String>,
+    },
+    Begin {
+        transaction: String,
+    },
+    Commit {
+        transaction: String,
+    },
+    Abort {
+        transaction: String,
+    },
+    Disconnect,
+}
+
+impl Listener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let inner = TcpListener::bind(addr).await?;
+        Ok(Listener {
+            inner,
+            heartbeat: None,
+            heartbeat_grace: DEFAULT_HEARTBEAT_GRACE,
+        })
+    }
+
+    /// Offer heartbeats at the given interval in both directions.
+    pub fn with_heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    /// How many of its heartbeat intervals a client may stay silent before
+    /// its connection fails with `StompError::PeerFailed`. Defaults to 2.
+    pub fn with_heartbeat_grace(mut self, intervals: u32) -> Self {
+        self.heartbeat_grace = intervals;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.local_addr()?)
+    }
+
+    /// Accepts the next connection, and waits for its `CONNECT` frame.
+    pub async fn accept(&mut self) -> Result<(ServerConnection, ServerSession)> {
+        let (sock, peer) = self.inner.accept().await?;
+        debug!("Accepted connection from {:?}", peer);
+        accept_with_transport(sock, self.heartbeat, self.heartbeat_grace).await
+    }
+}
+
+/// Performs the server's side of the handshake over any byte stream, offering
+/// heartbeats at the given interval. A client may stay silent for
+/// `heartbeat_grace` of its heartbeat intervals before its connection fails
+/// with `StompError::PeerFailed`; `Listener` uses 2.
+pub async fn accept_with_transport<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    transport: T,
+    heartbeat: Option<Duration>,
+    heartbeat_grace: u32,
+) -> Result<(ServerConnection, ServerSession)> {
+    let activity = Activity::new();
+    let mut conn = wrap(Tracked::new(transport, activity.clone()));
+
+    trace!("Awaiting connect frame");
+    let connect = loop {
+        match conn.next().await.transpose()? {
+            Some(FrameOrKeepAlive::Frame(frame)) => break frame,
+            Some(FrameOrKeepAlive::KeepAlive) => continue,
+            None => {
+                warn!("Connection closed before first frame received");
+                return Err(StompError::ProtocolError);
+            }
+        }
+    };
+
+    if connect.command != Command::Connect && connect.command != Command::Stomp {
+        refuse(&mut conn, &connect, "Expected a CONNECT frame").await?;
+        return Err(StompError::ProtocolError);
+    }
+    // Clients that only speak 1.0 do not send an `accept-version`.
+    let version = match connect.headers.get("accept-version".as_bytes()) {
+        Some(accepted) => Version::parse_list(accepted).into_iter().max(),
+        None => Some(Version::V1_0),
+    };
+    let version = match version {
+        Some(version) => version,
+        None => {
+            let message = format!(
+                "Supported protocol versions are {}",
+                Version::format_list(Version::ALL)
+            );
+            refuse(&mut conn, &connect, &message).await?;
+            return Err(StompError::ProtocolError);
+        }
+    };
+    debug!("Negotiated protocol version {}", version);
+    conn.set_version(version);
+
+    let heartbeat = heartbeat.filter(|_| version.supports_heartbeats());
+    let (cx, cy) = if version.supports_heartbeats() {
+        match parse_keepalive(connect.headers.get("heart-beat".as_bytes()).map(|s| &**s)) {
+            Ok(cxcy) => cxcy,
+            Err(e) => {
+                refuse(&mut conn, &connect, "Invalid heart-beat header").await?;
+                return Err(e);
+            }
+        }
+    } else {
+        (None, None)
+    };
+
+    debug!(
+        "heart-beat: ours:{:?}; client-transmit:{:?}; client-receive:{:?}",
+        heartbeat, cx, cy,
+    );
+    let c2s_ka = negotiate_heartbeat(cx, heartbeat);
+    let s2c_ka = negotiate_heartbeat(heartbeat, cy);
+
+    let session = format!("session-{}", NEXT_SESSION.fetch_add(1, Ordering::Relaxed));
+    let millis = heartbeat.unwrap_or_default().as_millis();
+    let connected = Frame {
+        command: Command::Connected,
+        headers: btreemap! {
+            "version".as_bytes().to_vec() => version.as_str().as_bytes().to_vec(),
+            "server".as_bytes().to_vec() => SERVER_NAME.as_bytes().to_vec(),
+            "session".as_bytes().to_vec() => session.as_bytes().to_vec(),
+            "heart-beat".as_bytes().to_vec() => format!("{},{}", millis, millis).into_bytes(),
+        },
+        body: Vec::new(),
+    };
+    trace!("Sending connected frame");
+    conn.send(FrameOrKeepAlive::Frame(connected)).await?;
+
+    let (s2c_tx, s2c_rx) = unbounded();
+    let (requests_tx, requests_rx) = channel(1);
+    let (fatal_tx, fatal_rx) = oneshot::channel();
+    let (sink, stream) = conn.split();
+    let c2s = run_c2s(
+        stream,
+        requests_tx,
+        fatal_tx,
+        version,
+        c2s_ka,
+        heartbeat_grace,
+        activity.clone(),
+    );
+    let conn = ServerConnection {
+        c2s: Some(c2s.boxed()),
+        s2c: run_s2c(sink, s2c_rx, fatal_rx, s2c_ka, activity).boxed(),
+    };
+    let session = ServerSession {
+        requests: requests_rx,
+        responder: Responder {
+            s2c: s2c_tx,
+            version,
+        },
+        session,
+        version,
+        connect_headers: connect.headers,
+    };
+    Ok((conn, session))
+}
+
+async fn run_c2s(
+    mut inner: impl Stream<Item = Result<FrameOrKeepAlive>> + Unpin,
+    mut requests: Sender<Request>,
+    fatal: oneshot::Sender<Frame>,
+    version: Version,
+    keepalive: Option<Duration>,
+    heartbeat_grace: u32,
+    activity: Activity,
+) -> Result<()> {
+    let ka_factor = cmp::max(heartbeat_grace, 1);
+    loop {
+        let it = if let Some(keepalive) = keepalive {
+            // Part of a frame arriving still shows the client is alive.
+            let deadline = || activity.last_read() + keepalive * ka_factor;
+            match timeout_at(deadline(), inner.next()).await {
+                Ok(it) => it.transpose()?,
+                Err(_) if deadline() > Instant::now() => continue,
+                Err(_) => return Err(StompError::PeerFailed),
+            }
+        } else {
+            inner.next().await.transpose()?
+        };
+
+        let frame = match it {
+            Some(FrameOrKeepAlive::Frame(frame)) => frame,
+            Some(FrameOrKeepAlive::KeepAlive) => {
+                debug!("Received keepalive.");
+                continue;
+            }
+            None => return Ok(()),
+        };
+
+        trace!(
+            "Received from client {:?}/{:?}",
+            frame.command,
+            frame.stringify_headers()
+        );
+        let receipt = frame.headers.get("receipt".as_bytes()).cloned();
+        let request = match Request::from_frame(frame, version) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Bad request from client: {}", e);
+                let _ = fatal.send(error_frame(&e.to_string(), receipt));
+                return Ok(());
+            }
+        };
+
+        let disconnect = request.kind == RequestKind::Disconnect;
+        if requests.send(request).await.is_err() {
+            debug!("Session dropped; ignoring further requests");
+            return Ok(());
+        }
+        if disconnect {
+            return Ok(());
+        }
+    }
+}
+
+async fn run_s2c(
+    mut inner: impl Sink<FrameOrKeepAlive, Error = StompError> + Unpin,
+    mut s2c_rx: UnboundedReceiver<Frame>,
+    mut fatal: oneshot::Receiver<Frame>,
+    keepalive: Option<Duration>,
+    activity: Activity,
+) -> Result<()> {
+    loop {
+        let tick = match keepalive {
+            Some(keepalive) => delay_until(activity.last_written() + keepalive).left_future(),
+            None => future::pending().right_future(),
+        };
+        let frame = select! {
+            frame = s2c_rx.next() => match frame {
+                Some(frame) => frame,
+                // The session may go away as a result of a fatal error.
+                None => match fatal.try_recv() {
+                    Ok(Some(frame)) => frame,
+                    _ => break,
+                },
+            },
+            frame = fatal => match frame {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            },
+            () = tick.fuse() => {
+                trace!("Timeout elapsed, sending keepalive");
+                inner.send(FrameOrKeepAlive::KeepAlive).await?;
+                continue;
+            }
+        };
+
+        trace!(
+            "Sending to client {:?}/{:?}",
+            frame.command,
+            frame.stringify_headers()
+        );
+        let is_error = frame.command == Command::Error;
+        inner.send(FrameOrKeepAlive::Frame(frame)).await?;
+        if is_error {
+            // The connection must be closed after an ERROR frame.
+            break;
+        }
+    }
+    debug!("Closing connection to client");
+    inner.close().await
+}
+
+impl Future for ServerConnection {
+    type Output = Result<()>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        trace!("Poll server to client");
+        if let Poll::Ready(val) = self.s2c.as_mut().poll(cx) {
+            info!("Server to client process finished: {:?}", val);
+            return Poll::Ready(val);
+        }
+
+        trace!("Poll client to server");
+        if let Some(c2s) = self.c2s.as_mut() {
+            if let Poll::Ready(val) = c2s.as_mut().poll(cx) {
+                info!("Client to server process finished: {:?}", val);
+                self.c2s = None;
+                val?;
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl ServerSession {
+    /// The id sent to the client in the `session` header.
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// The negotiated protocol version.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// The headers of the client's `CONNECT` frame, eg: `login` and `host`.
+    pub fn connect_headers(&self) -> &Headers {
+        &self.connect_headers
+    }
+
+    pub fn responder(&self) -> Responder {
+        self.responder.clone()
+    }
+}
+
+impl Stream for ServerSession {
+    type Item = Request;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.requests).poll_next(cx)
+    }
+}
+
+impl Responder {
+    // Headers that the library sets itself on a `MESSAGE` frame.
+    const RESERVED_HEADERS: &'static [&'static str] = &[
+        "destination",
+        "message-id",
+        "subscription",
+        "ack",
+        "content-length",
+    ];
+
+    /// The protocol version negotiated with the client.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Delivers a message to one of the client's subscriptions. When given,
+    /// `ack` is the id the client should acknowledge the message with; before
+    /// STOMP 1.2, clients acknowledge by `message-id` instead.
+    pub fn message(
+        &self,
+        subscription: &str,
+        message_id: &str,
+        ack: Option<&str>,
+        message: Message,
+    ) -> Result<()> {
+        let mut headers = message.headers;
+        for name in Self::RESERVED_HEADERS {
+            headers.remove(name.as_bytes());
+        }
+        headers.insert(
+            "destination".as_bytes().to_vec(),
+            message.destination.into_bytes(),
+        );
+        headers.insert(
+            "message-id".as_bytes().to_vec(),
+            message_id.as_bytes().to_vec(),
+        );
+        headers.insert(
+            "subscription".as_bytes().to_vec(),
+            subscription.as_bytes().to_vec(),
+        );
+        if let Some(ack) = ack.filter(|_| self.version >= Version::V1_2) {
+            headers.insert("ack".as_bytes().to_vec(), ack.as_bytes().to_vec());
+        }
+        headers.insert(
+            "content-length".as_bytes().to_vec(),
+            message.body.len().to_string().into_bytes(),
+        );
+        self.send(Frame {
+            command: Command::Message,
+            headers,
+            body: message.body,
+        })
+    }
+
+    pub fn receipt(&self, receipt_id: &str) -> Result<()> {
+        self.send(Frame {
+            command: Command::Receipt,
+            headers: btreemap! {
+                "receipt-id".as_bytes().to_vec() => receipt_id.as_bytes().to_vec(),
+            },
+            body: Vec::new(),
+        })
+    }
+
+    /// Reports an error to the client. The connection is closed once the
+    /// `ERROR` frame has been sent.
+    pub fn error(&self, message: &str, receipt_id: Option<&str>) -> Result<()> {
+        self.send(error_frame(
+            message,
+            receipt_id.map(|id| id.as_bytes().to_vec()),
+        ))
+    }
+
+    fn send(&self, frame: Frame) -> Result<()> {
+        self.s2c
+            .unbounded_send(frame)
+            .map_err(|_| StompError::Disconnected)
+    }
+}
+
+impl Request {
+    fn from_frame(frame: Frame, version: Version) -> Result<Self> {
+        let Frame {
+            command,
+            headers,
+            body,
+        } = frame;
+        let kind = match command {
+            Command::Send => RequestKind::Send {
+                destination: required(&headers, "destination")?,
+                transaction: optional(&headers, "transaction")?,
+                body,
+            },
+            Command::Subscribe => RequestKind::Subscribe {
+                destination: required(&headers, "destination")?,
+                id: required(&headers, "id")?,
+                ack: optional(&headers, "ack")?
+                    .map(|ack| ack.parse())
+                    .transpose()?
+                    .unwrap_or(AckMode::Auto),
+            },
+            Command::Unsubscribe => RequestKind::Unsubscribe {
+                id: required(&headers, "id")?,
+            },
+            Command::Ack => RequestKind::Ack {
+                id: required(&headers, ack_id_header(version))?,
+                transaction: optional(&headers, "transaction")?,
+            },
+            Command::Nack if !version.supports_nack() => {
+                return Err(StompError::NotSupported("NACK", version));
+            }
+            Command::Nack => RequestKind::Nack {
+                id: required(&headers, ack_id_header(version))?,
+                transaction: optional(&headers, "transaction")?,
+            },
+            Command::Begin => RequestKind::Begin {
+                transaction: required(&headers, "transaction")?,
+            },
+            Command::Commit => RequestKind::Commit {
+                transaction: required(&headers, "transaction")?,
+            },
+            Command::Abort => RequestKind::Abort {
+                transaction: required(&headers, "transaction")?,
+            },
+            Command::Disconnect => RequestKind::Disconnect,
+            _ => {
+                warn!("Unexpected frame type from client: {:?}", command);
+                return Err(StompError::ProtocolError);
+            }
+        };
+        Ok(Request {
+            kind,
+            receipt: optional(&headers, "receipt")?,
+            headers,
+        })
+    }
+}
+
+// The header of an `ACK` or `NACK` frame naming the message.
+fn ack_id_header(version: Version) -> &'static str {
+    if version >= Version::V1_2 {
+        "id"
+    } else {
+        "message-id"
+    }
+}
+
+fn optional(headers: &Headers, name: &str) -> Result<Option<String>> {
+    headers
+        .get(name.as_bytes())
+        .map(|v| Ok(std::str::from_utf8(v)?.to_string()))
+        .transpose()
+}
+
+fn required(headers: &Headers, name: &'static str) -> Result<String> {
+    optional(headers, name)?.ok_or(StompError::MissingHeader(name))
+}
+
+fn error_frame(message: &str, receipt_id: Option<Vec<u8>>) -> Frame {
+    let mut headers = btreemap! {
+        "message".as_bytes().to_vec() => message.as_bytes().to_vec(),
+    };
+    if let Some(receipt_id) = receipt_id {
+        headers.insert("receipt-id".as_bytes().to_vec(), receipt_id);
+    }
+    Frame {
+        command: Command::Error,
+        headers,
+        body: Vec::new(),
+    }
+}
+
+async fn refuse<F>(conn: &mut F, frame: &Frame, message: &str) -> Result<()>
+where
+    F: Sink<FrameOrKeepAlive, Error = StompError> + Unpin,
+{
+    warn!("Refusing connection: {}", message);
+    let mut error = error_frame(message, frame.headers.get("receipt".as_bytes()).cloned());
+    error.headers.insert(
+        "version".as_bytes().to_vec(),
+        Version::format_list(Version::ALL).into_bytes(),
+    );
+    conn.send(FrameOrKeepAlive::Frame(error)).await?;
+    conn.close().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::duplex;
+    use tokio_util::codec::Framed;
+
+    use crate::client::connect_with_transport;
+    use crate::connection::StompCodec;
+
+    fn frame(command: Command, headers: &[(&str, &str)]) -> Frame {
+        Frame {
+            command,
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_requests_from_a_client() {
+        env_logger::try_init().unwrap_or_default();
+        let (client_side, server_side) = duplex(4096);
+
+        let server = tokio::spawn(async move {
+            let (conn, mut session) =
+                accept_with_transport(server_side, None, DEFAULT_HEARTBEAT_GRACE)
+                    .await
+                    .expect("accept");
+            let conn = tokio::spawn(conn);
+            assert_eq!(session.version(), Version::V1_2);
+            assert_eq!(
+                session.connect_headers().get("login".as_bytes()),
+                Some(&b"guest".to_vec())
+            );
+
+            let request = session.next().await.expect("subscribe");
+            match request.kind {
+                RequestKind::Subscribe {
+                    destination,
+                    id,
+                    ack,
+                } => {
+                    assert_eq!(destination, "/queue/a");
+                    assert_eq!(ack, AckMode::ClientIndividual);
+                    let message = Message::new(&destination, b"hello");
+                    session
+                        .responder()
+                        .message(&id, "m-1", Some("a-1"), message)
+                        .expect("message");
+                }
+                other => panic!("Unexpected request: {:?}", other),
+            }
+
+            let request = session.next().await.expect("ack");
+            assert_eq!(
+                request.kind,
+                RequestKind::Ack {
+                    id: "a-1".to_string(),
+                    transaction: None
+                }
+            );
+
+            let request = session.next().await.expect("disconnect");
+            assert_eq!(request.kind, RequestKind::Disconnect);
+            let receipt = request.receipt.expect("receipt");
+            session.responder().receipt(&receipt).expect("receipt");
+            assert!(session.next().await.is_none(), "No more requests");
+            drop(session);
+            conn.await.expect("join")
+        });
+
+        let (conn, client) = connect_with_transport(
+            client_side,
+            Some(("guest", "guest")),
+            None,
+            Default::default(),
+        )
+        .await
+        .expect("connect");
+        let conn = tokio::spawn(conn);
+
+        let mut sub = client
+            .subscribe("/queue/a", "one", AckMode::ClientIndividual, Headers::new())
+            .await
+            .expect("subscribe");
+        let message = sub.next().await.expect("message");
+        assert_eq!(message.body(), b"hello");
+        message.ack().await.expect("ack");
+        client.disconnect().await.expect("disconnect");
+
+        server.await.expect("join").expect("server connection");
+        drop(sub);
+        conn.await.expect("join").expect("client connection");
+    }
+
+    #[tokio::test]
+    async fn acks_by_message_id_before_1_2() {
+        env_logger::try_init().unwrap_or_default();
+        let (client_side, server_side) = duplex(4096);
+
+        let server = tokio::spawn(async move {
+            let (conn, mut session) =
+                accept_with_transport(server_side, None, DEFAULT_HEARTBEAT_GRACE)
+                    .await
+                    .expect("accept");
+            let conn = tokio::spawn(conn);
+            assert_eq!(session.version(), Version::V1_1);
+
+            let request = session.next().await.expect("subscribe");
+            match request.kind {
+                RequestKind::Subscribe {
+                    destination, id, ..
+                } => {
+                    let message = Message::new(&destination, b"hello");
+                    session
+                        .responder()
+                        .message(&id, "m-1", Some("a-1"), message)
+                        .expect("message");
+                }
+                other => panic!("Unexpected request: {:?}", other),
+            }
+
+            let request = session.next().await.expect("ack");
+            assert_eq!(
+                request.kind,
+                RequestKind::Ack {
+                    id: "m-1".to_string(),
+                    transaction: None
+                }
+            );
+            assert_eq!(
+                request.headers.get("subscription".as_bytes()),
+                Some(&b"one".to_vec())
+            );
+            drop(session);
+            conn.await.expect("join")
+        });
+
+        let headers = btreemap! {
+            "accept-version".as_bytes().to_vec() => "1.0,1.1".as_bytes().to_vec(),
+        };
+        let (conn, client) = connect_with_transport(client_side, None, None, headers)
+            .await
+            .expect("connect");
+        assert_eq!(client.version(), Version::V1_1);
+        let conn = tokio::spawn(conn);
+
+        let mut sub = client
+            .subscribe("/queue/a", "one", AckMode::ClientIndividual, Headers::new())
+            .await
+            .expect("subscribe");
+        let message = sub.next().await.expect("message");
+        assert_eq!(message.headers().get("ack".as_bytes()), None);
+        message.ack().await.expect("ack");
+
+        server.await.expect("join").expect("server connection");
+        drop((sub, client));
+        let _ = conn.await.expect("join");
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_requests() {
+        env_logger::try_init().unwrap_or_default();
+        let (client_side, server_side) = duplex(4096);
+        let server = tokio::spawn(async move {
+            let (conn, session) = accept_with_transport(server_side, None, DEFAULT_HEARTBEAT_GRACE)
+                .await
+                .expect("accept");
+            let res = conn.await;
+            drop(session);
+            res
+        });
+
+        let mut peer = wrap(client_side);
+        let connect = frame(Command::Connect, &[("accept-version", "1.1,1.2")]);
+        peer.send(FrameOrKeepAlive::Frame(connect))
+            .await
+            .expect("send");
+        let subscribe = frame(Command::Subscribe, &[("id", "s"), ("receipt", "r")]);
+        peer.send(FrameOrKeepAlive::Frame(subscribe))
+            .await
+            .expect("send");
+
+        let connected = peer.next().await.expect("frame").expect("decode");
+        match connected {
+            FrameOrKeepAlive::Frame(frame) => assert_eq!(frame.command, Command::Connected),
+            other => panic!("Unexpected: {:?}", other),
+        }
+        let error = match peer.next().await.expect("frame").expect("decode") {
+            FrameOrKeepAlive::Frame(frame) => frame,
+            other => panic!("Unexpected: {:?}", other),
+        };
+        assert_eq!(error.command, Command::Error);
+        assert_eq!(
+            error.headers.get("message".as_bytes()),
+            Some(&b"Missing destination header".to_vec())
+        );
+        assert_eq!(
+            error.headers.get("receipt-id".as_bytes()),
+            Some(&b"r".to_vec())
+        );
+        assert!(peer.next().await.is_none(), "Connection closed");
+        server.await.expect("join").expect("server connection");
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_client_misses_heartbeats() {
+        env_logger::try_init().unwrap_or_default();
+        tokio::time::pause();
+        let (client_side, server_side) = duplex(4096);
+        let server = tokio::spawn(async move {
+            let (conn, _session) = accept_with_transport(
+                server_side,
+                Some(Duration::from_millis(10)),
+                DEFAULT_HEARTBEAT_GRACE,
+            )
+            .await
+            .expect("accept");
+            conn.await
+        });
+
+        let mut peer = wrap(client_side);
+        connect_peer(&mut peer).await;
+        // Longer than the default grace of two intervals allows.
+        tokio::time::advance(Duration::from_millis(60)).await;
+
+        let res = server.await.expect("join");
+        assert!(
+            matches!(res, Err(StompError::PeerFailed)),
+            "Result: {:?}",
+            res
+        );
+    }
+
+    #[tokio::test]
+    async fn tolerates_silence_within_the_heartbeat_grace() {
+        env_logger::try_init().unwrap_or_default();
+        tokio::time::pause();
+        let (client_side, server_side) = duplex(4096);
+        let server = tokio::spawn(async move {
+            let (conn, mut session) =
+                accept_with_transport(server_side, Some(Duration::from_millis(10)), 5)
+                    .await
+                    .expect("accept");
+            let conn = tokio::spawn(conn);
+            let request = session.next().await.expect("request");
+            assert_eq!(request.kind, RequestKind::Disconnect);
+            drop(session);
+            conn.await.expect("join")
+        });
+
+        let mut peer = wrap(client_side);
+        connect_peer(&mut peer).await;
+        tokio::time::advance(Duration::from_millis(60)).await;
+        peer.send(FrameOrKeepAlive::Frame(frame(Command::Disconnect, &[])))
+            .await
+            .expect("send");
+
+        let res = server.await.expect("join");
+        assert!(res.is_ok(), "Result: {:?}", res);
+    }
+
+    // Connects, promising a heartbeat every 20ms, and waits to be accepted.
+    async fn connect_peer<T: AsyncRead + AsyncWrite + Unpin>(peer: &mut Framed<T, StompCodec>) {
+        let connect = frame(
+            Command::Connect,
+            &[("accept-version", "1.2"), ("heart-beat", "20,0")],
+        );
+        peer.send(FrameOrKeepAlive::Frame(connect))
+            .await
+            .expect("send");
+        match peer.next().await.expect("frame").expect("decode") {
+            FrameOrKeepAlive::Frame(frame) => assert_eq!(frame.command, Command::Connected),
+            other => panic!("Unexpected: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::*;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+use crate::client::Client;
+use crate::connection::{self, ConnectReq, Connection};
+use crate::errors::*;
+use crate::protocol::Headers;
+
+/// As `connect`, but over TLS (ie: a `stomp+ssl` broker). The server's
+/// certificate is verified against `server_name` using the roots and
+/// settings in `config`; it is also the default virtual host.
+pub async fn connect_tls<A: ToSocketAddrs>(
+    a: A,
+    server_name: &str,
+    config: Arc<ClientConfig>,
+    credentials: Option<(&str, &str)>,
+    keepalive: Option<Duration>,
+    headers: Headers,
+) -> Result<(Connection, Client)> {
+    let dns_name = DNSNameRef::try_from_ascii_str(server_name)
+        .map_err(|_| StompError::InvalidServerName(server_name.to_string()))?;
+
+    let conn = TcpStream::connect(a).await?;
+    trace!("Starting TLS handshake with {:?}", server_name);
+    let conn = TlsConnector::from(config).connect(dns_name, conn).await?;
+
+    let mut req = ConnectReq::new(credentials, keepalive, headers);
+    req.virtual_host = Some(server_name.to_string());
+    let (mux, c2s_tx) = connection::connect(conn, req).await?;
+    let client = Client::new(c2s_tx, &mux);
+    Ok((mux, client))
+}
@@ -1,28 +1,32 @@
 use bytes::{BufMut, BytesMut};
 
 use crate::errors::*;
-use crate::protocol::{Frame, FrameOrKeepAlive};
+use crate::protocol::{Frame, FrameOrKeepAlive, Version};
 
-pub(crate) fn encode_frame(buf: &mut BytesMut, item: &FrameOrKeepAlive) -> Result<()> {
+pub(crate) fn encode_frame(
+    buf: &mut BytesMut,
+    item: &FrameOrKeepAlive,
+    version: Version,
+) -> Result<()> {
     match item {
-        FrameOrKeepAlive::Frame(ref frame) => encode_inner(buf, frame)?,
+        FrameOrKeepAlive::Frame(ref frame) => encode_inner(buf, frame, version)?,
         FrameOrKeepAlive::KeepAlive => encode_keepalive(buf)?,
     }
 
     Ok(())
 }
 
-fn encode_inner(buf: &mut BytesMut, frame: &Frame) -> Result<()> {
+fn encode_inner(buf: &mut BytesMut, frame: &Frame, version: Version) -> Result<()> {
     buf.put_slice(frame.command.as_str().as_bytes());
     buf.put_u8(b'\n');
 
     for (k, v) in frame.headers.iter() {
-        if k.len() == 0 {
+        if k.is_empty() {
             return Err(StompError::ProtocolError);
         }
-        encode_header_label(buf, k);
+        encode_header_label(buf, k, version)?;
         buf.put_u8(b':');
-        encode_header_label(buf, v);
+        encode_header_label(buf, v, version)?;
         buf.put_u8(b'\n');
     }
 
@@ -40,16 +44,26 @@ fn encode_keepalive(buf: &mut BytesMut) -> Result<()> {
     Ok(())
 }
 
-fn encode_header_label(buf: &mut BytesMut, label: &[u8]) {
+// STOMP 1.0 has no escapes, so cannot carry a newline in a header; nor can
+// 1.1 carry a carriage return.
+fn encode_header_label(buf: &mut BytesMut, label: &[u8], version: Version) -> Result<()> {
+    if version == Version::V1_0 {
+        if label.contains(&b'\n') {
+            return Err(StompError::ProtocolError);
+        }
+        buf.put_slice(label);
+        return Ok(());
+    }
     for c in label {
         match *c {
             b':' => buf.put_slice(b"\\c"),
-            b'\r' => buf.put_slice(b"\\r"),
+            b'\r' if version >= Version::V1_2 => buf.put_slice(b"\\r"),
             b'\n' => buf.put_slice(b"\\n"),
             b'\\' => buf.put_slice(b"\\\\"),
             _ => buf.put_u8(*c),
         }
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -69,12 +83,10 @@ mod tests {
         };
         let mut buf = BytesMut::new();
 
-        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame)).expect("encode frame");
+        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_2)
+            .expect("encode frame");
 
-        assert_eq!(
-            &*"SEND\n\n\0",
-            std::str::from_utf8(&buf).expect("from utf8")
-        );
+        assert_eq!("SEND\n\n\0", std::str::from_utf8(&buf).expect("from utf8"));
     }
 
     #[test]
@@ -86,10 +98,11 @@ mod tests {
         };
         let mut buf = BytesMut::new();
 
-        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame)).expect("encode frame");
+        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_2)
+            .expect("encode frame");
 
         assert_eq!(
-            &*"SEND\nhello:world\n\n\0",
+            "SEND\nhello:world\n\n\0",
             std::str::from_utf8(&buf).expect("from utf8")
         );
     }
@@ -103,10 +116,11 @@ mod tests {
         };
         let mut buf = BytesMut::new();
 
-        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame)).expect("encode frame");
+        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_2)
+            .expect("encode frame");
 
         assert_eq!(
-            &*"SEND\nfoo\\cbar:y\n\n\0",
+            "SEND\nfoo\\cbar:y\n\n\0",
             std::str::from_utf8(&buf).expect("from utf8")
         );
     }
@@ -120,10 +134,11 @@ mod tests {
         };
         let mut buf = BytesMut::new();
 
-        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame)).expect("encode frame");
+        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_2)
+            .expect("encode frame");
 
         assert_eq!(
-            &*"SEND\ndestination:/queue/hello\\cworld\n\n\0",
+            "SEND\ndestination:/queue/hello\\cworld\n\n\0",
             std::str::from_utf8(&buf).expect("from utf8")
         );
     }
@@ -137,10 +152,11 @@ mod tests {
         };
         let mut buf = BytesMut::new();
 
-        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame)).expect("encode frame");
+        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_2)
+            .expect("encode frame");
 
         assert_eq!(
-            &*"SEND\n\\n:y\n\n\0",
+            "SEND\n\\n:y\n\n\0",
             std::str::from_utf8(&buf).expect("from utf8")
         );
     }
@@ -154,10 +170,11 @@ mod tests {
         };
         let mut buf = BytesMut::new();
 
-        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame)).expect("encode frame");
+        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_2)
+            .expect("encode frame");
 
         assert_eq!(
-            &*"SEND\nheader:\\\\\n\n\0",
+            "SEND\nheader:\\\\\n\n\0",
             std::str::from_utf8(&buf).expect("from utf8")
         );
     }
@@ -172,14 +189,47 @@ mod tests {
 
         let mut buf = BytesMut::new();
 
-        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame)).expect("encode frame");
+        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_2)
+            .expect("encode frame");
 
         assert_eq!(
-            &*"SEND\nx:\\r\n\n\0",
+            "SEND\nx:\\r\n\n\0",
             std::str::from_utf8(&buf).expect("from utf8")
         );
     }
 
+    #[test]
+    fn should_not_escape_headers_in_1_0() {
+        let frame = Frame {
+            command: Command::Send,
+            headers: btreemap! {"x".into() => "a:b\\".into()},
+            body: Vec::new(),
+        };
+        let mut buf = BytesMut::new();
+
+        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_0)
+            .expect("encode frame");
+
+        assert_eq!(
+            "SEND\nx:a:b\\\n\n\0",
+            std::str::from_utf8(&buf).expect("from utf8")
+        );
+    }
+
+    #[test]
+    fn should_fail_on_newline_in_1_0() {
+        let frame = Frame {
+            command: Command::Send,
+            headers: btreemap! {"x".into() => "\n".into()},
+            body: Vec::new(),
+        };
+        let mut buf = BytesMut::new();
+
+        let res = encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_0);
+
+        assert!(res.is_err(), "Encoding should fail; got: {:?}", res);
+    }
+
     #[test]
     fn should_fail_on_empty_header_name() {
         let frame = Frame {
@@ -190,7 +240,7 @@ mod tests {
 
         let mut buf = BytesMut::new();
 
-        let res = encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame));
+        let res = encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_2);
 
         assert!(res.is_err(), "Encoding should fail; got: {:?}", res);
     }
@@ -204,21 +254,19 @@ mod tests {
         };
         let mut buf = BytesMut::new();
 
-        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame)).expect("encode frame");
+        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame), Version::V1_2)
+            .expect("encode frame");
 
-        assert_eq!(
-            &*"SEND\n\nx\0",
-            std::str::from_utf8(&buf).expect("from utf8")
-        );
+        assert_eq!("SEND\n\nx\0", std::str::from_utf8(&buf).expect("from utf8"));
     }
 
     #[test]
     fn should_encode_keepalive() {
         let mut buf = BytesMut::new();
 
-        encode_frame(&mut buf, &FrameOrKeepAlive::KeepAlive).expect("encode frame");
+        encode_frame(&mut buf, &FrameOrKeepAlive::KeepAlive, Version::V1_2).expect("encode frame");
 
-        assert_eq!(&*"\n", std::str::from_utf8(&buf).expect("from utf8"));
+        assert_eq!("\n", std::str::from_utf8(&buf).expect("from utf8"));
     }
 
     #[test]
@@ -229,15 +277,88 @@ mod tests {
         property(frames().filter(|frame| !frame.body.contains(&b'\0'))).check(|frame| {
             let mut buf = BytesMut::new();
 
-            encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame.clone())).expect("encode frame");
-            let parsed = parse_frame(&mut buf)
+            encode_frame(
+                &mut buf,
+                &FrameOrKeepAlive::Frame(frame.clone()),
+                Version::V1_2,
+            )
+            .expect("encode frame");
+            let parsed = parse_frame(&mut buf, Version::V1_2)
                 .expect("parse")
                 .expect("some frame")
                 .unwrap_frame();
 
             assert_eq!(frame, parsed);
             assert!(
-                buf.len() == 0,
+                buf.is_empty(),
+                "Remaining should be empty: {}",
+                String::from_utf8_lossy(&buf)
+            )
+        })
+    }
+
+    #[test]
+    fn should_round_trip_frames_with_crlf_line_endings() {
+        use crate::parser::parse_frame;
+
+        env_logger::try_init().unwrap_or(());
+        property(frames().filter(|frame| !frame.body.contains(&b'\0'))).check(|frame| {
+            let mut buf = BytesMut::new();
+
+            encode_frame(
+                &mut buf,
+                &FrameOrKeepAlive::Frame(frame.clone()),
+                Version::V1_2,
+            )
+            .expect("encode frame");
+            let mut buf = with_crlf(&buf);
+            let parsed = parse_frame(&mut buf, Version::V1_2)
+                .expect("parse")
+                .expect("some frame")
+                .unwrap_frame();
+
+            assert_eq!(frame, parsed);
+            assert!(
+                buf.is_empty(),
+                "Remaining should be empty: {}",
+                String::from_utf8_lossy(&buf)
+            )
+        })
+    }
+
+    #[test]
+    fn should_parse_mixed_keepalives_between_frames() {
+        use crate::parser::parse_frame;
+        use suppositions::generators::{booleans, vecs};
+
+        env_logger::try_init().unwrap_or(());
+        let items = vecs((
+            frames().filter(|frame| !frame.body.contains(&b'\0')),
+            vecs(booleans()),
+        ));
+        property(items).check(|items| {
+            let mut buf = BytesMut::new();
+            let mut expected = Vec::new();
+            for (frame, keepalives) in items {
+                let mut encoded = BytesMut::new();
+                let item = FrameOrKeepAlive::Frame(frame);
+                encode_frame(&mut encoded, &item, Version::V1_2).expect("encode frame");
+                buf.extend_from_slice(&with_crlf(&encoded));
+                expected.push(item);
+                for crlf in keepalives {
+                    buf.put_slice(if crlf { b"\r\n" } else { b"\n" });
+                    expected.push(FrameOrKeepAlive::KeepAlive);
+                }
+            }
+
+            let mut parsed = Vec::new();
+            while let Some(item) = parse_frame(&mut buf, Version::V1_2).expect("parse") {
+                parsed.push(item);
+            }
+
+            assert_eq!(expected, parsed);
+            assert!(
+                buf.is_empty(),
                 "Remaining should be empty: {}",
                 String::from_utf8_lossy(&buf)
             )
@@ -255,17 +376,22 @@ mod tests {
         println!("Frame: {:?}", frame);
 
         let mut buf = BytesMut::new();
-        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame.clone())).expect("encode frame");
+        encode_frame(
+            &mut buf,
+            &FrameOrKeepAlive::Frame(frame.clone()),
+            Version::V1_2,
+        )
+        .expect("encode frame");
         println!("Encoded: {:?}", String::from_utf8_lossy(&buf));
 
-        let parsed = parse_frame(&mut buf)
+        let parsed = parse_frame(&mut buf, Version::V1_2)
             .expect("parse")
             .expect("some frame")
             .unwrap_frame();
 
         assert_eq!(frame, parsed);
         assert!(
-            buf.len() == 0,
+            buf.is_empty(),
             "Remaining should be empty: {}",
             String::from_utf8_lossy(&buf)
         )
@@ -282,22 +408,46 @@ mod tests {
         println!("Frame: {:?}", frame);
 
         let mut buf = BytesMut::new();
-        encode_frame(&mut buf, &FrameOrKeepAlive::Frame(frame.clone())).expect("encode frame");
+        encode_frame(
+            &mut buf,
+            &FrameOrKeepAlive::Frame(frame.clone()),
+            Version::V1_2,
+        )
+        .expect("encode frame");
         println!("Encoded: {:?}", String::from_utf8_lossy(&buf));
 
-        let parsed = parse_frame(&mut buf)
+        let parsed = parse_frame(&mut buf, Version::V1_2)
             .expect("parse")
             .expect("some frame")
             .unwrap_frame();
 
         assert_eq!(frame, parsed);
         assert!(
-            buf.len() == 0,
+            buf.is_empty(),
             "Remaining should be empty: {}",
             String::from_utf8_lossy(&buf)
         )
     }
 
+    // Rewrites the line endings of an encoded frame's command and headers
+    // (which cannot contain a raw newline) as CRLF.
+    fn with_crlf(encoded: &[u8]) -> BytesMut {
+        let end = encoded
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .expect("end of headers")
+            + 2;
+        let mut buf = BytesMut::new();
+        for &b in &encoded[..end] {
+            if b == b'\n' {
+                buf.put_u8(b'\r');
+            }
+            buf.put_u8(b);
+        }
+        buf.put_slice(&encoded[end..]);
+        buf
+    }
+
     fn octet_vecs() -> impl Generator<Item = Vec<u8>> {
         use suppositions::generators::*;
         collections(u8s())
@@ -307,16 +457,21 @@ mod tests {
         use suppositions::generators::*;
         let commands = one_of(consts(Command::Send))
             .or(consts(Command::Connect))
+            .or(consts(Command::Stomp))
             .or(consts(Command::Subscribe))
             .or(consts(Command::Unsubscribe))
             .or(consts(Command::Disconnect))
             .or(consts(Command::Ack))
+            .or(consts(Command::Nack))
+            .or(consts(Command::Begin))
+            .or(consts(Command::Commit))
+            .or(consts(Command::Abort))
             .or(consts(Command::Connected))
             .or(consts(Command::Message))
             .or(consts(Command::Receipt))
             .or(consts(Command::Error));
 
-        let headers = collections((octet_vecs(), octet_vecs()).filter(|&(ref k, _)| k.len() != 0));
+        let headers = collections((octet_vecs(), octet_vecs()).filter(|(k, _)| !k.is_empty()));
 
         let bodies = vecs(u8s());
         (commands, headers, bodies).map(|(command, headers, body)| Frame {
@@ -0,0 +1,141 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::{ready, sink::Sink, stream::Stream};
+use log::*;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{connect_async, WebSocketStream};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::activity::Activity;
+use crate::client::Client;
+use crate::connection::{self, ConnectReq, Connection, SetVersion, StompCodec};
+use crate::errors::*;
+use crate::protocol::{FrameOrKeepAlive, Headers, Version};
+
+const STOMP_SUBPROTOCOLS: &str = "v12.stomp, v11.stomp, v10.stomp";
+
+// Carries one STOMP frame (or heartbeat) per WebSocket message.
+struct WsFramed<S> {
+    inner: WebSocketStream<S>,
+    codec: StompCodec,
+    read_buf: BytesMut,
+    activity: Activity,
+}
+
+/// As `connect`, but over a WebSocket (eg: RabbitMQ's web-stomp plugin),
+/// given a `ws://` URL.
+pub async fn connect_websocket(
+    url: &str,
+    credentials: Option<(&str, &str)>,
+    keepalive: Option<Duration>,
+    headers: Headers,
+) -> Result<(Connection, Client)> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(STOMP_SUBPROTOCOLS),
+    );
+    let mut req = ConnectReq::new(credentials, keepalive, headers);
+    req.virtual_host = request.uri().host().map(str::to_string);
+    trace!("Opening WebSocket to {:?}", url);
+    let (ws, _) = connect_async(request).await?;
+    connect_ws(ws, req).await
+}
+
+/// As `connect_websocket`, over an already established WebSocket.
+pub async fn connect_with_websocket<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    ws: WebSocketStream<S>,
+    credentials: Option<(&str, &str)>,
+    keepalive: Option<Duration>,
+    headers: Headers,
+) -> Result<(Connection, Client)> {
+    let req = ConnectReq::new(credentials, keepalive, headers);
+    connect_ws(ws, req).await
+}
+
+async fn connect_ws<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    ws: WebSocketStream<S>,
+    req: ConnectReq,
+) -> Result<(Connection, Client)> {
+    let activity = Activity::new();
+    let framed = WsFramed {
+        inner: ws,
+        codec: StompCodec::default(),
+        read_buf: BytesMut::new(),
+        activity: activity.clone(),
+    };
+    let (mux, c2s_tx) = connection::connect_framed(framed, activity, req).await?;
+
+    let client = Client::new(c2s_tx, &mux);
+    Ok((mux, client))
+}
+
+impl<S> SetVersion for WsFramed<S> {
+    fn set_version(&mut self, version: Version) {
+        self.codec.version = version;
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for WsFramed<S> {
+    type Item = Result<FrameOrKeepAlive>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(item) = this.codec.decode(&mut this.read_buf)? {
+                return Poll::Ready(Some(Ok(item)));
+            }
+
+            let msg = ready!(Pin::new(&mut this.inner).poll_next(cx));
+            if let Some(Ok(_)) = msg {
+                this.activity.read();
+            }
+            match msg {
+                Some(Ok(WsMessage::Text(text))) => this.read_buf.extend_from_slice(text.as_bytes()),
+                Some(Ok(WsMessage::Binary(data))) => this.read_buf.extend_from_slice(&data),
+                Some(Ok(WsMessage::Ping(_))) | Some(Ok(WsMessage::Pong(_))) => {}
+                Some(Ok(WsMessage::Close(reason))) => {
+                    debug!("WebSocket closed: {:?}", reason);
+                    return Poll::Ready(None);
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<FrameOrKeepAlive> for WsFramed<S> {
+    type Error = StompError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_ready(cx).map_err(Into::into)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: FrameOrKeepAlive) -> Result<()> {
+        let mut buf = BytesMut::new();
+        self.codec.encode(item, &mut buf)?;
+        // Prefer text messages, as some brokers do not accept binary ones.
+        let msg = match String::from_utf8(buf.to_vec()) {
+            Ok(text) => WsMessage::Text(text),
+            Err(e) => WsMessage::Binary(e.into_bytes()),
+        };
+        Pin::new(&mut self.inner).start_send(msg)?;
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+        self.activity.written();
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(Into::into)
+    }
+}
@@ -0,0 +1,25 @@
+// Helpers for tests that play the part of a broker by hand.
+#![allow(dead_code)]
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Reads the next frame as text, skipping any heartbeats.
+pub async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+    let mut buf = Vec::new();
+    loop {
+        let b = stream.read_u8().await.expect("read");
+        match b {
+            b'\0' => return String::from_utf8(buf).expect("utf8"),
+            b'\n' if buf.is_empty() => continue,
+            b => buf.push(b),
+        }
+    }
+}
+
+pub fn header<'a>(frame: &'a str, name: &str) -> Option<&'a str> {
+    let prefix = format!("{}:", name);
+    frame
+        .lines()
+        .find(|l| l.starts_with(&prefix))
+        .map(|l| &l[prefix.len()..])
+}
@@ -0,0 +1,111 @@
+#![cfg(feature = "tls")]
+
+mod common;
+
+use std::sync::Arc;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+use stomping::rustls::{Certificate, ClientConfig, NoClientAuth, PrivateKey, ServerConfig};
+use stomping::*;
+
+use common::{header, read_frame};
+
+fn self_signed() -> (Certificate, PrivateKey) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .expect("generate certificate");
+    (
+        Certificate(cert.serialize_der().expect("serialize certificate")),
+        PrivateKey(cert.serialize_private_key_der()),
+    )
+}
+
+#[tokio::test]
+async fn can_connect_over_tls() {
+    env_logger::try_init().unwrap_or_default();
+    let (cert, key) = self_signed();
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config
+        .set_single_cert(vec![cert.clone()], key)
+        .expect("server certificate");
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+
+    let server = tokio::spawn(async move {
+        let (conn, _) = listener.accept().await.expect("accept");
+        let mut conn = acceptor.accept(conn).await.expect("tls accept");
+
+        let frame = read_frame(&mut conn).await;
+        assert!(frame.starts_with("CONNECT\n"), "frame: {:?}", frame);
+        conn.write_all(b"CONNECTED\nversion:1.2\n\n\0")
+            .await
+            .expect("write");
+
+        let frame = read_frame(&mut conn).await;
+        assert!(frame.starts_with("DISCONNECT\n"), "frame: {:?}", frame);
+        let receipt = header(&frame, "receipt").expect("receipt header");
+        conn.write_all(format!("RECEIPT\nreceipt-id:{}\n\n\0", receipt).as_bytes())
+            .await
+            .expect("write");
+        conn.shutdown().await.expect("shutdown");
+    });
+
+    let mut client_config = ClientConfig::new();
+    client_config
+        .root_store
+        .add(&cert)
+        .expect("add root certificate");
+
+    let (conn, client) = connect_tls(
+        addr,
+        "localhost",
+        Arc::new(client_config),
+        None,
+        None,
+        Default::default(),
+    )
+    .await
+    .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    client.disconnect().await.expect("disconnect");
+    server.await.expect("server");
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+}
+
+#[tokio::test]
+async fn rejects_untrusted_certificates() {
+    env_logger::try_init().unwrap_or_default();
+    let (cert, key) = self_signed();
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config
+        .set_single_cert(vec![cert], key)
+        .expect("server certificate");
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+
+    tokio::spawn(async move {
+        let (conn, _) = listener.accept().await.expect("accept");
+        let _ = acceptor.accept(conn).await;
+    });
+
+    let res = connect_tls(
+        addr,
+        "localhost",
+        Arc::new(ClientConfig::new()),
+        None,
+        None,
+        Default::default(),
+    )
+    .await;
+    assert!(res.is_err(), "Connection should fail");
+}
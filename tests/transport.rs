@@ -0,0 +1,222 @@
+mod common;
+
+use std::time::Duration;
+
+use futures::stream::StreamExt;
+use tokio::io::{duplex, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use stomping::*;
+
+use common::{header, read_frame};
+
+#[tokio::test]
+async fn can_run_over_an_in_memory_pipe() {
+    env_logger::try_init().unwrap_or_default();
+    let (client_side, mut server_side) = duplex(4096);
+
+    let server = tokio::spawn(async move {
+        let frame = read_frame(&mut server_side).await;
+        assert!(frame.starts_with("CONNECT\n"), "frame: {:?}", frame);
+        assert_eq!(header(&frame, "host"), Some("localhost"));
+        server_side
+            .write_all(b"CONNECTED\nversion:1.2\n\n\0")
+            .await
+            .expect("write");
+
+        let frame = read_frame(&mut server_side).await;
+        assert!(frame.starts_with("SUBSCRIBE\n"), "frame: {:?}", frame);
+        assert_eq!(header(&frame, "destination"), Some("/queue/a"));
+        let id = header(&frame, "id").expect("id header").to_string();
+        server_side
+            .write_all(
+                format!(
+                    "MESSAGE\nsubscription:{}\nmessage-id:1\ndestination:/queue/a\n\nhello\0",
+                    id
+                )
+                .as_bytes(),
+            )
+            .await
+            .expect("write");
+
+        let frame = read_frame(&mut server_side).await;
+        assert!(frame.starts_with("DISCONNECT\n"), "frame: {:?}", frame);
+        let receipt = header(&frame, "receipt").expect("receipt header");
+        server_side
+            .write_all(format!("RECEIPT\nreceipt-id:{}\n\n\0", receipt).as_bytes())
+            .await
+            .expect("write");
+    });
+
+    let (conn, client) = connect_with_transport(client_side, None, None, Default::default())
+        .await
+        .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    let mut sub = client
+        .subscribe("/queue/a", "one", AckMode::Auto, Default::default())
+        .await
+        .expect("subscribe");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"hello");
+
+    client.disconnect().await.expect("disconnect");
+    server.await.expect("server");
+    drop(sub);
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+}
+
+#[tokio::test]
+async fn builder_dials_a_custom_transport() {
+    env_logger::try_init().unwrap_or_default();
+    let broker = Broker::new();
+
+    let options = Client::builder()
+        .address("broker.example:61613")
+        .credentials("guest", "guest")
+        .versions(&[Version::V1_1, Version::V1_2])
+        .transport(move || {
+            let (client_side, server_side) = duplex(4096);
+            let broker = broker.clone();
+            tokio::spawn(async move { broker.serve(server_side).await });
+            async { Ok(client_side) }
+        });
+    let (conn, client) = options.connect().await.expect("connect");
+    let conn_task = tokio::spawn(conn);
+    assert_eq!(client.version(), Version::V1_2);
+
+    client.disconnect().await.expect("disconnect");
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+}
+
+#[tokio::test]
+async fn builder_connects_over_a_transport() {
+    env_logger::try_init().unwrap_or_default();
+    let (client_side, mut server_side) = duplex(4096);
+
+    let server = tokio::spawn(async move {
+        let frame = read_frame(&mut server_side).await;
+        assert!(frame.starts_with("CONNECT\n"), "frame: {:?}", frame);
+        assert_eq!(header(&frame, "host"), Some("vhost"));
+        assert_eq!(header(&frame, "login"), Some("guest"));
+        assert_eq!(header(&frame, "accept-version"), Some("1.1,1.2"));
+        server_side
+            .write_all(b"CONNECTED\nversion:1.1\n\n\0")
+            .await
+            .expect("write");
+        server_side
+    });
+
+    let (conn, client) = Client::builder()
+        .virtual_host("vhost")
+        .credentials("guest", "guest")
+        .versions(&[Version::V1_1, Version::V1_2])
+        .connect_with_transport(client_side)
+        .await
+        .expect("connect");
+    assert_eq!(client.version(), Version::V1_1);
+    let _server_side = server.await.expect("server");
+    drop(conn);
+}
+
+// Replies to a CONNECT as a 1.0 server would, without naming a version.
+// Returns the version negotiated.
+async fn connect_to_1_0_server(options: ConnectOptions) -> Result<Version, StompError> {
+    let (client_side, mut server_side) = duplex(4096);
+    let server = tokio::spawn(async move {
+        read_frame(&mut server_side).await;
+        server_side
+            .write_all(b"CONNECTED\nsession:1\n\n\0")
+            .await
+            .expect("write");
+        server_side
+    });
+    let res = options.connect_with_transport(client_side).await;
+    let _server_side = server.await.expect("server");
+    res.map(|(_conn, client)| client.version())
+}
+
+#[tokio::test]
+async fn servers_without_a_version_speak_1_0() {
+    env_logger::try_init().unwrap_or_default();
+    let version = connect_to_1_0_server(Client::builder())
+        .await
+        .expect("connect");
+    assert_eq!(version, Version::V1_0);
+
+    let res = connect_to_1_0_server(Client::builder().versions(&[Version::V1_2])).await;
+    match res {
+        Err(StompError::UnsupportedVersion(version)) => assert_eq!(version, "1.0"),
+        other => panic!(
+            "Expected an unsupported version; got: {:?}",
+            other.map(|_| ())
+        ),
+    }
+}
+
+#[tokio::test]
+async fn connect_takes_any_socket_address() {
+    env_logger::try_init().unwrap_or_default();
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("local_addr");
+
+    let server = tokio::spawn(async move {
+        let mut hosts = Vec::new();
+        let mut socks = Vec::new();
+        for _ in 0..3 {
+            let (mut sock, _) = listener.accept().await.expect("accept");
+            let frame = read_frame(&mut sock).await;
+            hosts.push(header(&frame, "host").map(str::to_string));
+            sock.write_all(b"CONNECTED\nversion:1.2\n\n\0")
+                .await
+                .expect("write");
+            socks.push(sock);
+        }
+        (hosts, socks)
+    });
+
+    // A bare socket address names no host, so we leave it to the broker.
+    let (_conn, client) = connect(addr, None, None, Default::default())
+        .await
+        .expect("connect");
+    assert_eq!(client.version(), Version::V1_2);
+    let named = format!("localhost:{}", addr.port());
+    let _named = connect(&named, None, None, Default::default())
+        .await
+        .expect("connect");
+    let _pair = connect(("localhost", addr.port()), None, None, Default::default())
+        .await
+        .expect("connect");
+
+    let (hosts, _socks) = server.await.expect("server");
+    assert_eq!(
+        hosts,
+        vec![
+            None,
+            Some("localhost".to_string()),
+            Some("localhost".to_string())
+        ]
+    );
+}
+
+#[tokio::test]
+async fn builder_gives_up_after_connect_timeout() {
+    env_logger::try_init().unwrap_or_default();
+    // Accepts connections, but never answers them.
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("local_addr").to_string();
+
+    let res = Client::builder()
+        .address(&addr)
+        .connect_timeout(Duration::from_millis(10))
+        .connect()
+        .await;
+    drop(listener);
+
+    match res {
+        Err(StompError::ConnectTimeout) => {}
+        other => panic!("Expected a timeout; got: {:?}", other.map(|_| ())),
+    }
+}
@@ -0,0 +1,86 @@
+#![cfg(feature = "websocket")]
+mod common;
+
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use stomping::*;
+
+use common::header;
+
+async fn next_text<S>(ws: &mut S) -> String
+where
+    S: futures::Stream<Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    loop {
+        match ws.next().await.expect("message").expect("ws") {
+            WsMessage::Text(text) if text.trim_matches('\n').is_empty() => continue,
+            WsMessage::Text(text) => return text.trim_end_matches('\0').to_string(),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn can_run_over_a_websocket() {
+    env_logger::try_init().unwrap_or_default();
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("local_addr");
+
+    let server = tokio::spawn(async move {
+        let (sock, _) = listener.accept().await.expect("accept");
+        let mut ws = tokio_tungstenite::accept_async(sock)
+            .await
+            .expect("accept ws");
+
+        let frame = next_text(&mut ws).await;
+        assert!(frame.starts_with("CONNECT\n"), "frame: {:?}", frame);
+        ws.send(WsMessage::Text("CONNECTED\nversion:1.2\n\n\0".into()))
+            .await
+            .expect("send");
+
+        let frame = next_text(&mut ws).await;
+        assert!(frame.starts_with("SUBSCRIBE\n"), "frame: {:?}", frame);
+        let id = header(&frame, "id").expect("id header").to_string();
+        // Binary messages carry frames just as well as text ones.
+        ws.send(WsMessage::Binary(
+            format!(
+                "MESSAGE\nsubscription:{}\nmessage-id:1\ndestination:/queue/a\n\nhello\0",
+                id
+            )
+            .into_bytes(),
+        ))
+        .await
+        .expect("send");
+
+        let frame = next_text(&mut ws).await;
+        assert!(frame.starts_with("DISCONNECT\n"), "frame: {:?}", frame);
+        let receipt = header(&frame, "receipt").expect("receipt header");
+        ws.send(WsMessage::Text(format!(
+            "RECEIPT\nreceipt-id:{}\n\n\0",
+            receipt
+        )))
+        .await
+        .expect("send");
+    });
+
+    let url = format!("ws://{}/ws", addr);
+    let (conn, client) = connect_websocket(&url, None, None, Default::default())
+        .await
+        .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    let mut sub = client
+        .subscribe("/queue/a", "one", AckMode::Auto, Default::default())
+        .await
+        .expect("subscribe");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"hello");
+
+    client.disconnect().await.expect("disconnect");
+    server.await.expect("server");
+    drop(sub);
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+}
@@ -1,6 +1,5 @@
 #![cfg(not(feature = "skip-end-to-end"))]
-use env_logger;
-use tokio;
+#![allow(unexpected_cfgs)]
 
 #[macro_use]
 extern crate log;
@@ -9,19 +8,37 @@ use std::time::Duration;
 
 use futures::stream::StreamExt;
 use stomping::*;
+use tokio::net::TcpListener;
 use uuid::Uuid;
 
+// Runs against an embedded broker, unless `STOMP_BROKER` names a real one
+// (eg: `localhost:61613`).
+async fn broker_addr() -> String {
+    if let Ok(addr) = std::env::var("STOMP_BROKER") {
+        return addr;
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("local_addr");
+    tokio::spawn(Broker::new().listen(listener));
+    addr.to_string()
+}
+
+// Brokers such as RabbitMQ treat the `host` header as the virtual host, which
+// would otherwise default to the host part of the broker's address.
+fn vhost() -> Headers {
+    std::env::var("STOMP_VHOST")
+        .map(|vhost| std::iter::once(("host".as_bytes().to_vec(), vhost.into_bytes())).collect())
+        .unwrap_or_default()
+}
+
 #[tokio::test]
 async fn can_round_trip_text() {
     env_logger::try_init().unwrap_or_default();
-    let (conn, mut client) = connect(
-        ("localhost", 61613),
-        Some(("guest", "guest")),
-        None,
-        Default::default(),
-    )
-    .await
-    .expect("connect");
+    let addr = broker_addr().await;
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
+    assert!(client.server().is_some(), "Server names itself");
     let conn_task = tokio::spawn(async {
         debug!("Starting connection");
         let res = conn.await;
@@ -41,10 +58,10 @@ async fn can_round_trip_text() {
     client.publish(&queue, body).await.expect("publish");
 
     info!("Consuming from queue");
-    let frame = sub.next().await.expect("consume_next");
+    let message = sub.next().await.expect("consume_next");
     info!("Consumed item");
 
-    assert_eq!(&*body, &*frame.body);
+    assert_eq!(body, message.body());
     client.disconnect().await.expect("disconnect");
     let res = conn_task.await;
     assert!(res.is_ok(), "Conection exited normally");
@@ -53,14 +70,10 @@ async fn can_round_trip_text() {
 #[tokio::test]
 async fn can_round_trip_binary_blobs() {
     env_logger::try_init().unwrap_or_default();
-    let (conn, mut client) = connect(
-        ("localhost", 61613),
-        Some(("guest", "guest")),
-        None,
-        Default::default(),
-    )
-    .await
-    .expect("connect");
+    let addr = broker_addr().await;
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
     let conn_task = tokio::spawn(conn);
 
     let body = b"\x00\x01\x02\x03";
@@ -72,8 +85,8 @@ async fn can_round_trip_binary_blobs() {
         .expect("subscribe");
     client.publish(&queue, body).await.expect("publish");
 
-    let frame = sub.next().await.expect("consume_next");
-    assert_eq!(frame.body, body);
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), body);
     client.disconnect().await.expect("disconnect");
     let res = conn_task.await;
     assert!(res.is_ok(), "Conection exited normally");
@@ -82,14 +95,10 @@ async fn can_round_trip_binary_blobs() {
 #[tokio::test]
 async fn client_acks_should_allow_redelivery() {
     env_logger::try_init().unwrap_or_default();
-    let (conn, mut client) = connect(
-        ("localhost", 61613),
-        Some(("guest", "guest")),
-        None,
-        Default::default(),
-    )
-    .await
-    .expect("connect");
+    let addr = broker_addr().await;
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
     let conn_task = tokio::spawn(conn);
 
     let body = b"42";
@@ -104,8 +113,8 @@ async fn client_acks_should_allow_redelivery() {
         .expect("subscribe");
     client.publish(&queue, body).await.expect("publish");
 
-    let frame = sub.next().await.expect("consume_next");
-    assert_eq!(frame.body, body);
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), body);
 
     debug!("Disconnecting without acking");
     // Disconnect
@@ -116,14 +125,9 @@ async fn client_acks_should_allow_redelivery() {
     assert!(res.is_ok(), "Conection exited normally");
     debug!("First connection done");
 
-    let (conn, mut client) = connect(
-        ("localhost", 61613),
-        Some(("guest", "guest")),
-        None,
-        Default::default(),
-    )
-    .await
-    .expect("connect");
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
     let conn_task = tokio::spawn(conn);
 
     let mut sub = client
@@ -131,8 +135,8 @@ async fn client_acks_should_allow_redelivery() {
         .await
         .expect("subscribe");
     info!("Subscribed on second connection; awaiting next");
-    let frame = sub.next().await.expect("consume_next");
-    assert_eq!(frame.body, body);
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), body);
     client.disconnect().await.expect("disconnect");
     let res = conn_task.await;
     assert!(res.is_ok(), "Conection exited normally");
@@ -141,15 +145,11 @@ async fn client_acks_should_allow_redelivery() {
 #[tokio::test]
 async fn can_encode_headers_correctly() {
     env_logger::try_init().unwrap_or_default();
+    let addr = broker_addr().await;
     debug!("Connecting");
-    let (conn, mut client) = connect(
-        ("localhost", 61613),
-        Some(("guest", "guest")),
-        None,
-        Default::default(),
-    )
-    .await
-    .expect("connect");
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
     let conn_task = tokio::spawn(conn);
 
     let body = b"42";
@@ -161,12 +161,44 @@ async fn can_encode_headers_correctly() {
         .expect("subscribe");
     client.publish(&queue, body).await.expect("publish");
 
-    let frame = sub.next().await.expect("consume_next");
-    println!("h: {:?}", frame.headers);
-    assert_eq!(
-        std::str::from_utf8(&frame.headers["destination".as_bytes()]).expect("from utf8"),
-        queue
-    );
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.destination(), Some(&*queue));
+    client.disconnect().await.expect("disconnect");
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+}
+
+#[tokio::test]
+async fn can_send_custom_headers() {
+    env_logger::try_init().unwrap_or_default();
+    let addr = broker_addr().await;
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    let queue = format!("/queue/can_send_custom_headers-{}", Uuid::new_v4());
+
+    let mut sub = client
+        .subscribe(&queue, "one", AckMode::Auto, Default::default())
+        .await
+        .expect("subscribe");
+    let msg = Message::new(&queue, b"42")
+        .content_type("text/plain")
+        .correlation_id("c-42")
+        .header("x-canary", "Hi!");
+    client.send(msg).await.expect("send");
+
+    let message = sub.next().await.expect("consume_next");
+    let header = |k: &str| {
+        message
+            .headers()
+            .get(k.as_bytes())
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    };
+    assert_eq!(header("content-type"), Some("text/plain".to_string()));
+    assert_eq!(header("correlation-id"), Some("c-42".to_string()));
+    assert_eq!(header("x-canary"), Some("Hi!".to_string()));
     client.disconnect().await.expect("disconnect");
     let res = conn_task.await;
     assert!(res.is_ok(), "Conection exited normally");
@@ -175,14 +207,10 @@ async fn can_encode_headers_correctly() {
 #[tokio::test]
 async fn should_allow_acking_individual_messages() {
     env_logger::try_init().unwrap_or_default();
-    let (conn, mut client) = connect(
-        ("localhost", 61613),
-        Some(("guest", "guest")),
-        None,
-        Default::default(),
-    )
-    .await
-    .expect("connect");
+    let addr = broker_addr().await;
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
     let conn_task = tokio::spawn(conn);
 
     let queue = format!(
@@ -198,11 +226,11 @@ async fn should_allow_acking_individual_messages() {
     client.publish(&queue, b"second").await.expect("publish");
     client.publish(&queue, b"third").await.expect("publish");
 
-    let frame = sub.next().await.expect("consume_next");
-    assert_eq!(frame.body, b"first");
-    let frame = sub.next().await.expect("consume_next");
-    assert_eq!(frame.body, b"second");
-    client.ack(&frame.headers).await.expect("ack");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"first");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"second");
+    message.ack().await.expect("ack");
 
     // Disconnect
     drop(client);
@@ -210,24 +238,223 @@ async fn should_allow_acking_individual_messages() {
     let res = conn_task.await;
     assert!(res.is_ok(), "Conection exited normally");
 
-    let (conn, mut client) = connect(
-        ("localhost", 61613),
-        Some(("guest", "guest")),
-        None,
-        Default::default(),
-    )
-    .await
-    .expect("connect");
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
     let conn_task = tokio::spawn(conn);
 
     let mut sub = client
         .subscribe(&queue, "one", AckMode::ClientIndividual, Default::default())
         .await
         .expect("subscribe");
-    let frame = sub.next().await.expect("consume_next");
-    assert_eq!(frame.body, b"first");
-    let frame = sub.next().await.expect("consume_next");
-    assert_eq!(frame.body, b"third");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"first");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"third");
+    client.disconnect().await.expect("disconnect");
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+}
+
+#[tokio::test]
+async fn client_acks_should_be_cumulative() {
+    env_logger::try_init().unwrap_or_default();
+    let addr = broker_addr().await;
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    let queue = format!("/queue/client_acks_should_be_cumulative-{}", Uuid::new_v4());
+
+    let mut sub = client
+        .subscribe(&queue, "one", AckMode::Client, Default::default())
+        .await
+        .expect("subscribe");
+    client.publish(&queue, b"first").await.expect("publish");
+    client.publish(&queue, b"second").await.expect("publish");
+    client.publish(&queue, b"third").await.expect("publish");
+
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"first");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"second");
+    message.ack().await.expect("ack");
+
+    // Disconnect
+    drop(client);
+    drop(sub);
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    let mut sub = client
+        .subscribe(&queue, "one", AckMode::Client, Default::default())
+        .await
+        .expect("subscribe");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"third");
+    client.disconnect().await.expect("disconnect");
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+}
+
+#[tokio::test]
+async fn should_allow_acking_up_to_a_message() {
+    env_logger::try_init().unwrap_or_default();
+    let addr = broker_addr().await;
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    let queue = format!(
+        "/queue/should_allow_acking_up_to_a_message-{}",
+        Uuid::new_v4()
+    );
+
+    let mut sub = client
+        .subscribe(&queue, "one", AckMode::ClientIndividual, Default::default())
+        .await
+        .expect("subscribe");
+    client.publish(&queue, b"first").await.expect("publish");
+    client.publish(&queue, b"second").await.expect("publish");
+    client.publish(&queue, b"third").await.expect("publish");
+
+    sub.next().await.expect("consume_next");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"second");
+    message.ack_up_to().await.expect("ack_up_to");
+
+    // Disconnect
+    drop(client);
+    drop(sub);
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    let mut sub = client
+        .subscribe(&queue, "one", AckMode::ClientIndividual, Default::default())
+        .await
+        .expect("subscribe");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"third");
+    client.disconnect().await.expect("disconnect");
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+}
+
+#[tokio::test]
+async fn nacked_messages_should_be_redelivered() {
+    env_logger::try_init().unwrap_or_default();
+    let addr = broker_addr().await;
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    let queue = format!(
+        "/queue/nacked_messages_should_be_redelivered-{}",
+        Uuid::new_v4()
+    );
+
+    let mut sub = client
+        .subscribe(&queue, "one", AckMode::ClientIndividual, Default::default())
+        .await
+        .expect("subscribe");
+    client.publish(&queue, b"first").await.expect("publish");
+
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"first");
+    message.nack().await.expect("nack");
+
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"first");
+    message.ack().await.expect("ack");
+
+    client.disconnect().await.expect("disconnect");
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+}
+
+#[tokio::test]
+async fn only_committed_transactions_should_be_delivered() {
+    env_logger::try_init().unwrap_or_default();
+    let addr = broker_addr().await;
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    let queue = format!(
+        "/queue/only_committed_transactions_should_be_delivered-{}",
+        Uuid::new_v4()
+    );
+
+    let mut sub = client
+        .subscribe(&queue, "one", AckMode::Auto, Default::default())
+        .await
+        .expect("subscribe");
+
+    let tx = client.begin().await.expect("begin");
+    tx.publish(&queue, b"aborted").await.expect("publish");
+    drop(tx);
+
+    let tx = client.begin().await.expect("begin");
+    tx.publish(&queue, b"committed").await.expect("publish");
+    tx.commit().await.expect("commit");
+
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"committed");
+
+    client.disconnect().await.expect("disconnect");
+    let res = conn_task.await;
+    assert!(res.is_ok(), "Conection exited normally");
+}
+
+#[tokio::test]
+async fn can_reuse_subscription_id_after_unsubscribe() {
+    env_logger::try_init().unwrap_or_default();
+    let addr = broker_addr().await;
+    let (conn, client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
+    let conn_task = tokio::spawn(conn);
+
+    let queue = format!(
+        "/queue/can_reuse_subscription_id_after_unsubscribe-{}",
+        Uuid::new_v4()
+    );
+
+    let sub = client
+        .subscribe(&queue, "one", AckMode::Auto, Default::default())
+        .await
+        .expect("subscribe");
+    sub.unsubscribe().await.expect("unsubscribe");
+
+    let sub = client
+        .subscribe(&queue, "one", AckMode::Auto, Default::default())
+        .await
+        .expect("subscribe");
+    drop(sub);
+
+    let mut sub = client
+        .subscribe(&queue, "one", AckMode::Auto, Default::default())
+        .await
+        .expect("subscribe");
+    client.publish(&queue, b"first").await.expect("publish");
+
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"first");
+
     client.disconnect().await.expect("disconnect");
     let res = conn_task.await;
     assert!(res.is_ok(), "Conection exited normally");
@@ -238,14 +465,10 @@ async fn should_allow_acking_individual_messages() {
 #[tokio::test]
 async fn should_allow_timeout_on_consume() {
     env_logger::try_init().unwrap_or_default();
-    let (conn, mut client) = connect(
-        ("localhost", 61613),
-        Some(("guest", "guest")),
-        None,
-        Default::default(),
-    )
-    .await
-    .expect("connect");
+    let addr = broker_addr().await;
+    let (conn, mut client) = connect(&addr, Some(("guest", "guest")), None, vhost())
+        .await
+        .expect("connect");
     let conn_task = tokio::spawn(conn);
 
     let queue = format!(
@@ -270,8 +493,8 @@ async fn should_allow_timeout_on_consume() {
     client.publish(&queue, b"first").await.expect("publish");
     // was maybe_consume_next(timeout)
     let resp = sub.next().await.expect("consume_next");
-    let frame = resp.expect("a message");
-    assert_eq!(frame.body, b"first");
+    let message = resp.expect("a message");
+    assert_eq!(message.body(), b"first");
     let res = conn_task.await;
     assert!(res.is_ok(), "Conection exited normally");
 }
@@ -281,11 +504,12 @@ async fn should_allow_timeout_on_consume() {
 #[ignore]
 async fn thing_to_test_timeouts() {
     env_logger::try_init().unwrap_or_default();
-    let (conn, mut client) = connect(
-        ("localhost", 61613),
+    let addr = broker_addr().await;
+    let (conn, client) = connect(
+        &addr,
         Some(("guest", "guest")),
         Some(Duration::from_millis(500)),
-        Default::default(),
+        vhost(),
     )
     .await
     .expect("connect");
@@ -298,8 +522,8 @@ async fn thing_to_test_timeouts() {
         .await
         .expect("subscribe");
 
-    let frame = sub.next().await.expect("consume_next");
-    assert_eq!(frame.body, b"first");
+    let message = sub.next().await.expect("consume_next");
+    assert_eq!(message.body(), b"first");
     client.disconnect().await.expect("disconnect");
     let res = conn_task.await;
     assert!(res.is_ok(), "Conection exited normally");